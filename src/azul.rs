@@ -26,6 +26,53 @@ pub fn size_of_stuff() {
 
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AzulError {
+    InvalidPlayerCount(u8),
+    StartTileInBag,
+    NotOnWall(Tile),
+    InvalidWallRow(usize),
+    /// Row and column on the wall, counted from 0
+    WallTaken { row: usize, column: usize, color: Tile },
+    FactoriesNotEmpty,
+    GameNotOver,
+    TakeStartTile,
+    MarketEmpty,
+    NotInMarket(Tile),
+    FactoryOutOfBounds(usize),
+    NotInFactory { factory: usize, color: Tile },
+    PatternHasOtherColor { pattern: usize, color: Tile, existing: Tile },
+    PatternFull(usize),
+    InvalidPattern(usize),
+    NoLegalMoves,
+    /// A position in text that couldn't be read, and what was wrong with it
+    InvalidPosition(&'static str),
+}
+impl std::fmt::Display for AzulError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AzulError::InvalidPlayerCount(players) => write!(f, "{} is not a valid amount of players", players),
+            AzulError::StartTileInBag => write!(f, "The start tile doesn't go in the bag"),
+            AzulError::NotOnWall(tile) => write!(f, "{:?} is not a valid tile on the wall", tile),
+            AzulError::InvalidWallRow(row) => write!(f, "{} is not a valid row on the wall", row),
+            AzulError::WallTaken { row, column, color } => write!(f, "Wall cell ({}, {}) already has {:?}", row + 1, column + 1, color),
+            AzulError::FactoriesNotEmpty => write!(f, "Cannot fill, factories are not empty"),
+            AzulError::GameNotOver => write!(f, "The game is not over yet"),
+            AzulError::TakeStartTile => write!(f, "You can't take the start tile specifically"),
+            AzulError::MarketEmpty => write!(f, "Market is empty"),
+            AzulError::NotInMarket(color) => write!(f, "Market does not contain {:?}", color),
+            AzulError::FactoryOutOfBounds(factory) => write!(f, "Factory {} is out of bounds", factory),
+            AzulError::NotInFactory { factory, color } => write!(f, "Factory {} does not contain {:?}", factory, color),
+            AzulError::PatternHasOtherColor { pattern, color, existing } => write!(f, "Pattern line {} already contains {:?}, not {:?}", pattern, existing, color),
+            AzulError::PatternFull(pattern) => write!(f, "Pattern line {} is full", pattern),
+            AzulError::InvalidPattern(pattern) => write!(f, "{} is not a valid destination", pattern),
+            AzulError::NoLegalMoves => write!(f, "There are no legal moves"),
+            AzulError::InvalidPosition(reason) => write!(f, "Invalid position: {}", reason),
+        }
+    }
+}
+impl std::error::Error for AzulError {}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Tile {
 //    None,
@@ -44,6 +91,31 @@ impl Default for Tile {
     }
 }
 
+impl Tile {
+    /// How the tile is written in positions, black is K so it doesn't clash with blue
+    pub fn letter(&self) -> char {
+        match self {
+            Tile::Start => 'S',
+            Tile::Blue => 'B',
+            Tile::Yellow => 'Y',
+            Tile::Red => 'R',
+            Tile::Black => 'K',
+            Tile::Teal => 'T',
+        }
+    }
+    pub fn from_letter(letter: char) -> Option<Tile> {
+        match letter {
+            'S' => Some(Tile::Start),
+            'B' => Some(Tile::Blue),
+            'Y' => Some(Tile::Yellow),
+            'R' => Some(Tile::Red),
+            'K' => Some(Tile::Black),
+            'T' => Some(Tile::Teal),
+            _ => None
+        }
+    }
+}
+
 impl IntoIterator for Tile {
     type Item = Tile;
     type IntoIter = TileIter;
@@ -99,7 +171,7 @@ impl Iterator for TileIter {
 
 
 // factory, color, pattern line
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GameMove (pub usize, pub Tile, pub usize);
 impl Default for GameMove {
     fn default() -> Self {
@@ -118,6 +190,105 @@ impl IntoIterator for GameMove {
     }
 }
 
+/// A move that `State::validate` has accepted, only good for the state it was validated against.
+/// Applying it to another state, like one after a refill, can point at a factory that isn't there.
+/// Only `validate` and `legal_moves` make them, so they're not checked again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidMove (GameMove);
+
+/// What `State::unmake_move` needs to take back a move
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undo {
+    /// Enough to put the taken tiles back where they came from
+    Move {
+        player: u8,
+        // What the factory held before, empty when taking from the market
+        factory: Factory,
+        source: u8,
+        color: Tile,
+        start: bool,
+        pattern: u8,
+        placed: u8,
+        floored: u8,
+        boxed: u8,
+        bumped: Option<Tile>
+    },
+    /// The whole state, for moves that end the round
+    Full(Box<State>)
+}
+
+// At most 9 factories with 4 colors and 5 colors in the market, each to 6 places
+const MAX_MOVES: usize = 256;
+
+/// The moves from `State::legal_moves`. An `ArrayVec` would need a default `ValidMove`,
+/// and that would be one nobody validated, so this keeps its own array
+#[derive(Clone, Copy)]
+pub struct LegalMoves {
+    moves: [ValidMove; MAX_MOVES],
+    len: usize,
+}
+impl LegalMoves {
+    fn new() -> LegalMoves {
+        // Never handed out, only the first `len` are
+        LegalMoves { moves: [ValidMove(GameMove(0, Tile::Start, 0)); MAX_MOVES], len: 0 }
+    }
+    fn push(&mut self, valid_move: ValidMove) {
+        self.moves[self.len] = valid_move;
+        self.len += 1;
+    }
+}
+impl Deref for LegalMoves {
+    type Target = [ValidMove];
+
+    fn deref(&self) -> &[ValidMove] {
+        &self.moves[..self.len]
+    }
+}
+impl std::fmt::Debug for LegalMoves {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+impl IntoIterator for LegalMoves {
+    type Item = ValidMove;
+    type IntoIter = std::iter::Take<std::array::IntoIter<ValidMove, MAX_MOVES>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIterator::into_iter(self.moves).take(self.len)
+    }
+}
+impl<'a> IntoIterator for &'a LegalMoves {
+    type Item = &'a ValidMove;
+    type IntoIter = std::slice::Iter<'a, ValidMove>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl ValidMove {
+    pub fn game_move(&self) -> GameMove {
+        self.0
+    }
+}
+
+const COLORS: [Tile; 5] = [Tile::Blue, Tile::Yellow, Tile::Red, Tile::Black, Tile::Teal];
+
+// Every way to order the colors
+fn color_permutations() -> &'static [[Tile; 5]] {
+    static PERMUTATIONS: std::sync::OnceLock<Vec<[Tile; 5]>> = std::sync::OnceLock::new();
+    PERMUTATIONS.get_or_init(|| {
+        let mut permutations = Vec::with_capacity(120);
+        for n in 0..5usize.pow(5) {
+            let digits = [n % 5, n / 5 % 5, n / 25 % 5, n / 125 % 5, n / 625];
+            if (0..5).all(|i| (0..i).all(|j| digits[i] != digits[j])) {
+                permutations.push([COLORS[digits[0]], COLORS[digits[1]], COLORS[digits[2]], COLORS[digits[3]], COLORS[digits[4]]]);
+            }
+        }
+        permutations
+    })
+}
+
 #[derive(Debug)]
 pub struct GameMoveIter {
     players: u8,
@@ -132,12 +303,12 @@ impl GameMoveIter {
     }
 }
 
-fn get_n_factories(players: u8) -> Result<u8, &'static str> {
+fn get_n_factories(players: u8) -> Result<u8, AzulError> {
     return match players {
         2 => Ok(5),
         3 => Ok(7),
         4 => Ok(9),
-        _ => Err("Not a valid amount of players")
+        _ => Err(AzulError::InvalidPlayerCount(players))
     };
 }
 
@@ -197,12 +368,36 @@ impl Default for Bag {
 }
 
 impl Bag {
+    fn from_counts([blue, yellow, red, black, teal]: [u8; 5]) -> Bag {
+        Bag { blue, yellow, red, black, teal }
+    }
     fn len(&self) -> u8 {
         self.blue + self.yellow + self.red + self.black + self.teal
     }
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+    // The start tile never leaves the market and the floors, so only colors get here
+    fn remove(&mut self, tile: Tile, amount: u8) {
+        match tile {
+            Tile::Start => debug_assert!(false, "the start tile doesn't go in the bag"),
+            Tile::Blue => self.blue -= amount,
+            Tile::Yellow => self.yellow -= amount,
+            Tile::Red => self.red -= amount,
+            Tile::Black => self.black -= amount,
+            Tile::Teal => self.teal -= amount,
+        }
+    }
+    fn add(&mut self, tile: Tile, amount: u8) {
+        match tile {
+            Tile::Start => debug_assert!(false, "the start tile doesn't go in the bag"),
+            Tile::Blue => self.blue += amount,
+            Tile::Yellow => self.yellow += amount,
+            Tile::Red => self.red += amount,
+            Tile::Black => self.black += amount,
+            Tile::Teal => self.teal += amount,
+        }
+    }
     fn hash(&self) -> [u8; 5] {
         [
             self.blue,
@@ -212,6 +407,10 @@ impl Bag {
             self.teal
         ]
     }
+    fn zobrist(&self, offset: usize) -> u64 {
+        self.hash().iter().enumerate()
+            .fold(0u64, |sum, (color, n)| sum.wrapping_add(ZOBRIST[offset + color * 21 + *n as usize]))
+    }
 }
 
 /*#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
@@ -260,8 +459,9 @@ impl From<tinyvec::ArrayVec<[Tile; 128]>> for Bag {
 }*/
 
 
+/// The tiles on one factory, up to four
 #[derive(Default, Debug, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
-struct Factory (tinyvec::ArrayVec<[Tile; 4]>);
+pub struct Factory (tinyvec::ArrayVec<[Tile; 4]>);
 impl Clone for Factory {
     //#[no_alloc]
     fn clone(&self) -> Self {
@@ -282,6 +482,21 @@ impl DerefMut for Factory {
 }
 
 
+impl Factory {
+    // Factories are hashed as a whole, so the same factory twice doesn't cancel out
+    fn zobrist(&self) -> u64 {
+        let mut counts = [0; 5];
+        for tile in self.iter() {
+            counts[*tile as usize - 1] += 1;
+        }
+        let sum = counts.iter().enumerate()
+            .fold(0u64, |sum, (color, n)| sum.wrapping_add(ZOBRIST[ZOBRIST_FACTORY + color * 5 + n]));
+        zobrist_mix(sum)
+    }
+}
+
+pub type Factories = tinyvec::ArrayVec<[Factory; 9]>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct Market (tinyvec::ArrayVec<[Tile; 28]>);
 impl Default for Market {
@@ -292,6 +507,16 @@ impl Default for Market {
         Market(market)
     }
 }
+impl Market {
+    fn zobrist(&self) -> u64 {
+        let mut counts = [0; 6];
+        for tile in self.iter() {
+            counts[*tile as usize] += 1;
+        }
+        counts.iter().enumerate()
+            .fold(0u64, |sum, (tile, n)| sum.wrapping_add(ZOBRIST[ZOBRIST_MARKET + tile * 21 + n]))
+    }
+}
 impl Deref for Market {
     type Target = tinyvec::ArrayVec<[Tile; 28]>;
 
@@ -307,18 +532,35 @@ impl DerefMut for Market {
 
 type Patterns = [tinyvec::ArrayVec<[Tile; 5]>; 5];
 
+/// Points for placing a tile on the wall, split by the direction they came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Connected {
+    pub row: u8,
+    pub column: u8,
+}
+impl Connected {
+    pub fn total(&self) -> u8 {
+        self.row + self.column
+    }
+}
+
+// The floor line has 7 slots, each taking away this many points
+pub(crate) const FLOOR_PENALTIES: [i16; 7] = [1, 1, 2, 2, 2, 3, 3];
+
+type Floor = tinyvec::ArrayVec<[Tile; 7]>;
+
 type Row  = [bool; 5];
 type Wall = [Row;  5];
 
 #[derive(Debug, Clone, Default, Copy, PartialEq, Eq, Hash)]
 struct Board {
-    score: u8,
+    score: i16,
     wall: Wall,
-    floor: tinyvec::ArrayVec<[Tile; 20]>,
+    floor: Floor,
     patterns: Patterns,
 }
 impl Board {
-    fn wall_index(color: Tile, row: usize) -> Result<usize, &'static str> {
+    fn wall_index(color: Tile, row: usize) -> Result<usize, AzulError> {
         match row {
             0 => {
                 match color {
@@ -327,7 +569,7 @@ impl Board {
                     Tile::Red => Ok(2),
                     Tile::Black => Ok(3),
                     Tile::Teal => Ok(4),
-                    _ => return Err("Not a valid tile on the wall")
+                    _ => Err(AzulError::NotOnWall(color))
                 }
             },
             1 => {
@@ -337,7 +579,7 @@ impl Board {
                     Tile::Red => Ok(3),
                     Tile::Black => Ok(4),
                     Tile::Teal => Ok(0),
-                    _ => return Err("Not a valid tile on the wall")
+                    _ => Err(AzulError::NotOnWall(color))
                 }
             },
             2 => {
@@ -347,7 +589,7 @@ impl Board {
                     Tile::Red => Ok(4),
                     Tile::Black => Ok(0),
                     Tile::Teal => Ok(1),
-                    _ => return Err("Not a valid tile on the wall")
+                    _ => Err(AzulError::NotOnWall(color))
                 }
             },
             3 => {
@@ -357,7 +599,7 @@ impl Board {
                     Tile::Red => Ok(0),
                     Tile::Black => Ok(1),
                     Tile::Teal => Ok(2),
-                    _ => return Err("Not a valid tile on the wall")
+                    _ => Err(AzulError::NotOnWall(color))
                 }
             },
             4 => {
@@ -367,16 +609,15 @@ impl Board {
                     Tile::Red => Ok(1),
                     Tile::Black => Ok(2),
                     Tile::Teal => Ok(3),
-                    _ => return Err("Not a valid tile on the wall")
+                    _ => Err(AzulError::NotOnWall(color))
                 }
             },
-            _ => return Err("Not a valid row on the wall")
+            _ => Err(AzulError::InvalidWallRow(row))
         }
     }
-    fn connected(&self, coordinate: (usize, usize)) -> u8 {
+    fn connected(&self, coordinate: (usize, usize)) -> Connected {
         let wall = self.wall;
 
-        let mut sum = 0;
         let mut active = false;
 
         let mut count = 0;
@@ -392,7 +633,7 @@ impl Board {
                 count += 1;
             }
         }
-        sum += count;
+        let horizontal = count;
 
         let mut active = false;
         let mut count = 0;
@@ -408,30 +649,189 @@ impl Board {
                 count += 1;
             }
         }
-        sum += count;
+        let vertical = count;
+
+        // A run only scores in the directions where the tile has neighbours,
+        // and a tile without any neighbours scores a single point
+        Connected {
+            row: if horizontal > 1 || vertical == 1 { horizontal } else { 0 },
+            column: if vertical > 1 { vertical } else { 0 }
+        }
+    }
+    /// Tiles that don't fit on the floor go straight to the box lid
+    /// Returns the tile that ended up in the box lid, if any
+    fn add_to_floor(floor: &mut Floor, tile: Tile, box_top: &mut Bag) -> Option<Tile> {
+        if floor.len() < floor.capacity() {
+            floor.push(tile);
+            None
+        }
+        else if tile == Tile::Start {
+            // The start tile always gets a place, pushing a regular tile off the floor
+            floor.sort_unstable();
+            let bumped = floor.pop();
+            if let Some(tile) = bumped {
+                box_top.add(tile, 1);
+            }
+            floor.push(Tile::Start);
+            bumped
+        }
+        else {
+            box_top.add(tile, 1);
+            Some(tile)
+        }
+    }
+    // Like `wall_index`, for tiles that are known to be colors
+    fn column(color: Tile, row: usize) -> usize {
+        debug_assert!(color != Tile::Start, "the start tile isn't on the wall");
+        (color as usize + 4 + row) % 5
+    }
+    /// A pattern line can't take a color that is already on the wall in its row
+    fn check_wall(&self, color: Tile, pattern: usize) -> Result<(), AzulError> {
+        let row = pattern.checked_sub(1).ok_or(AzulError::InvalidPattern(pattern))?;
+        let index = Board::wall_index(color, row)?;
+        if self.wall[row][index] {
+            return Err(AzulError::WallTaken { row, column: index, color })
+        }
+        Ok(())
+    }
+    /// Whether the floor (0) or a pattern line (1-5) can take tiles of a color
+    fn check_pattern(&self, color: Tile, pattern: usize) -> Result<(), AzulError> {
+        match pattern {
+            0 => Ok(()),
+            1..=5 => {
+                self.check_wall(color, pattern)?;
+                let target = &self.patterns[pattern - 1];
+                if target.first().is_some() && target[0] != color {
+                    return Err(AzulError::PatternHasOtherColor { pattern, color, existing: target[0] })
+                }
+                if target.len() == pattern {
+                    return Err(AzulError::PatternFull(pattern))
+                }
+                Ok(())
+            },
+            _ => Err(AzulError::InvalidPattern(pattern))
+        }
+    }
+    fn zobrist(&self, player: usize) -> u64 {
+        let offset = ZOBRIST_BOARDS + player * ZOBRIST_BOARD;
+        let mut hash = zobrist_mix(ZOBRIST[offset].wrapping_add(self.score as u64));
+        for row in 0..5 {
+            for column in 0..5 {
+                if self.wall[row][column] {
+                    hash = hash.wrapping_add(ZOBRIST[offset + 1 + row * 5 + column]);
+                }
+            }
+            hash = hash.wrapping_add(self.pattern_zobrist(player, row + 1));
+        }
+        hash.wrapping_add(self.floor_zobrist(player))
+    }
+    fn floor_zobrist(&self, player: usize) -> u64 {
+        let offset = ZOBRIST_BOARDS + player * ZOBRIST_BOARD + 1 + 25;
+        let mut counts = [0; 6];
+        for tile in self.floor.iter() {
+            counts[*tile as usize] += 1;
+        }
+        counts.iter().enumerate()
+            .fold(0u64, |sum, (tile, n)| sum.wrapping_add(ZOBRIST[offset + tile * 8 + n]))
+    }
+    fn pattern_zobrist(&self, player: usize, pattern: usize) -> u64 {
+        let offset = ZOBRIST_BOARDS + player * ZOBRIST_BOARD + 1 + 25 + 6 * 8;
+        match self.patterns[pattern - 1].first() {
+            Some(color) => {
+                let n = self.patterns[pattern - 1].len();
+                ZOBRIST[offset + (pattern - 1) * 30 + *color as usize * 5 + n - 1]
+            },
+            None => 0
+        }
+    }
+    fn has_full_row(&self) -> bool {
+        self.wall.iter().any(|row| row.iter().all(|x| *x))
+    }
+    /// End of game bonuses: 2 per full row, 7 per full column and 10 per full color
+    fn bonus(&self) -> Result<u8, AzulError> {
+        let mut bonus = 0;
+        for row in 0..5 {
+            if self.wall[row].iter().all(|x| *x) {
+                bonus += 2;
+            }
+        }
+        for column in 0..5 {
+            if self.wall.iter().all(|row| row[column]) {
+                bonus += 7;
+            }
+        }
+        for color in COLORS.iter() {
+            let mut full = true;
+            for row in 0..5 {
+                if !self.wall[row][Board::wall_index(*color, row)?] {
+                    full = false;
+                    break;
+                }
+            }
+            if full {
+                bonus += 10;
+            }
+        }
+        Ok(bonus)
+    }
+}
 
-        return sum
+// Random keys for Zobrist hashing, one for each value every part of the state can have.
+// Parts are added together, so taking a part out again is a subtraction
+const ZOBRIST_PLAYER: usize = 0;
+const ZOBRIST_BAG: usize = ZOBRIST_PLAYER + 4;
+const ZOBRIST_BOX_TOP: usize = ZOBRIST_BAG + 5 * 21;
+const ZOBRIST_MARKET: usize = ZOBRIST_BOX_TOP + 5 * 21;
+const ZOBRIST_FACTORY: usize = ZOBRIST_MARKET + 6 * 21;
+const ZOBRIST_BOARDS: usize = ZOBRIST_FACTORY + 5 * 5;
+// Score, wall, floor and pattern lines
+const ZOBRIST_BOARD: usize = 1 + 25 + 6 * 8 + 5 * 6 * 5;
+const ZOBRIST_SIZE: usize = ZOBRIST_BOARDS + 4 * ZOBRIST_BOARD;
+
+const ZOBRIST: [u64; ZOBRIST_SIZE] = zobrist_keys();
+
+const fn zobrist_mix(x: u64) -> u64 {
+    // The splitmix64 finalizer
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+const fn zobrist_keys() -> [u64; ZOBRIST_SIZE] {
+    let mut keys = [0; ZOBRIST_SIZE];
+    let mut i = 0;
+    while i < ZOBRIST_SIZE {
+        keys[i] = zobrist_mix((i as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+        i += 1;
     }
+    keys
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct State {
     player: u8,
     box_top: Bag,
     bag: Bag,
     market: Market,
-    factories: tinyvec::ArrayVec<[Factory; 5]>,  // TODO set to 9?
-    boards: tinyvec::ArrayVec<[Board; 2]>        // TODO set to 4?
+    factories: Factories,
+    boards: tinyvec::ArrayVec<[Board; 4]>,
+    zobrist: u64
+}
+// Equal states have equal Zobrist hashes, no need to look at the rest
+impl Hash for State {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.zobrist.hash(state);
+    }
 }
 impl State {
-    pub fn new(players: u8) -> Result<State, &'static str> {
+    pub fn new(players: u8) -> Result<State, AzulError> {
         let n_factories = get_n_factories(players)?;
-        let mut factories = tinyvec::ArrayVec::<[Factory; 5]>::new();
+        let mut factories = Factories::new();
         for _ in 0..n_factories {
             factories.push(Factory::default())
         }
 
-        let mut boards = tinyvec::ArrayVec::<[Board; 2]>::new();
+        let mut boards = tinyvec::ArrayVec::<[Board; 4]>::new();
         for _ in 0..players {
             boards.push(Board::default());
         }
@@ -448,213 +848,318 @@ impl State {
             bag: Bag::default(),
             market: Market::default(),
             factories: factories,
-            boards: boards
+            boards,
+            zobrist: 0
         };
+        let game = State { zobrist: game.compute_zobrist(), ..game };
 
         Ok(game)
     }
-    pub fn fill(&mut self, mut rng: StdRng) -> Result<(), &'static str> {
+    /// The Zobrist hash of the state, kept up to date as the state changes
+    pub fn zobrist(&self) -> u64 {
+        self.zobrist
+    }
+    fn compute_zobrist(&self) -> u64 {
+        let mut hash = ZOBRIST[ZOBRIST_PLAYER + self.player as usize]
+            .wrapping_add(self.bag.zobrist(ZOBRIST_BAG))
+            .wrapping_add(self.box_top.zobrist(ZOBRIST_BOX_TOP))
+            .wrapping_add(self.market.zobrist());
+        for factory in &self.factories {
+            hash = hash.wrapping_add(factory.zobrist());
+        }
+        for (player, board) in self.boards.iter().enumerate() {
+            hash = hash.wrapping_add(board.zobrist(player));
+        }
+        hash
+    }
+    /// Has to be called after changing the fields by hand
+    fn rehash(&mut self) {
+        self.zobrist = self.compute_zobrist();
+    }
+    // Everything but the player and the factories, which is what scoring touches
+    fn boards_zobrist(&self) -> u64 {
+        let mut hash = self.box_top.zobrist(ZOBRIST_BOX_TOP)
+            .wrapping_add(self.market.zobrist());
+        for (player, board) in self.boards.iter().enumerate() {
+            hash = hash.wrapping_add(board.zobrist(player));
+        }
+        hash
+    }
+    // The parts of the state taking tiles can touch
+    fn move_zobrist(&self, player: usize, pattern: usize) -> u64 {
+        let board = &self.boards[player];
+        let mut hash = self.box_top.zobrist(ZOBRIST_BOX_TOP)
+            .wrapping_add(self.market.zobrist())
+            .wrapping_add(board.floor_zobrist(player));
+        if pattern != 0 {
+            hash = hash.wrapping_add(board.pattern_zobrist(player, pattern));
+        }
+        hash
+    }
+    fn bag_zobrist(&self) -> u64 {
+        let mut hash = self.bag.zobrist(ZOBRIST_BAG)
+            .wrapping_add(self.box_top.zobrist(ZOBRIST_BOX_TOP));
+        for factory in &self.factories {
+            hash = hash.wrapping_add(factory.zobrist());
+        }
+        hash
+    }
+    pub fn fill(&mut self, mut rng: StdRng) -> Result<(), AzulError> {
         for factory in &self.factories {
             if factory.len() != 0 {
-                return Err("Cannot fill, factories are not empty")
+                return Err(AzulError::FactoriesNotEmpty)
             };
         };
-        for factory in &mut self.factories {
+        let before = self.bag_zobrist();
+        'fill: for factory in &mut self.factories {
             for _ in 0..4 {
                 if self.bag.is_empty() && !self.box_top.is_empty() {
                     self.bag = self.box_top;
                     self.box_top = Bag { blue: 0, yellow: 0, red: 0, black: 0, teal: 0};
                 }
                 else if self.bag.is_empty() {
-                    return Ok(())
+                    break 'fill;
                 }
-                else {
-                    let choices = [Tile::Blue, Tile::Yellow, Tile::Red, Tile::Black, Tile::Teal];
-                    let weights = [self.bag.blue, self.bag.yellow, self.bag.red, self.bag.black, self.bag.teal];
-                    let dist = WeightedIndex::new(&weights).unwrap();
-                    let tile = choices[dist.sample(&mut rng)];
-
-                    match tile {
-                        Tile::Start => {return Err("This should never happen")}
-                        Tile::Blue => {self.bag.blue -= 1}
-                        Tile::Yellow => {self.bag.yellow -= 1}
-                        Tile::Red => {self.bag.red -= 1}
-                        Tile::Black => {self.bag.black -= 1}
-                        Tile::Teal => {self.bag.teal -= 1}
-                    }
-                    factory.push(tile)
+
+                let choices = [Tile::Blue, Tile::Yellow, Tile::Red, Tile::Black, Tile::Teal];
+                let weights = [self.bag.blue, self.bag.yellow, self.bag.red, self.bag.black, self.bag.teal];
+                let dist = WeightedIndex::new(&weights).unwrap();
+                let tile = choices[dist.sample(&mut rng)];
+
+                match tile {
+                    Tile::Start => {return Err(AzulError::StartTileInBag)}
+                    Tile::Blue => {self.bag.blue -= 1}
+                    Tile::Yellow => {self.bag.yellow -= 1}
+                    Tile::Red => {self.bag.red -= 1}
+                    Tile::Black => {self.bag.black -= 1}
+                    Tile::Teal => {self.bag.teal -= 1}
                 }
+                factory.push(tile)
             }
             factory.sort_unstable();
         };
         self.factories.sort_unstable();
+
+        self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(self.bag_zobrist());
+        debug_assert_eq!(self.zobrist, self.compute_zobrist());
+        Ok(())
+    }
+    /// Fills the factories with tiles that were drawn before, taking them out of the bag
+    fn refill(&mut self, factories: Factories) -> Result<(), AzulError> {
+        for factory in &self.factories {
+            if factory.len() != 0 {
+                return Err(AzulError::FactoriesNotEmpty)
+            };
+        };
+
+        let before = self.bag_zobrist();
+        // When there's more than the bag holds, the box lid was emptied into it along the way
+        let drawn = factories.iter().map(|factory| factory.len()).sum::<usize>();
+        if drawn > self.bag.len() as usize {
+            self.bag.blue += self.box_top.blue;
+            self.bag.yellow += self.box_top.yellow;
+            self.bag.red += self.box_top.red;
+            self.bag.black += self.box_top.black;
+            self.bag.teal += self.box_top.teal;
+            self.box_top = Bag { blue: 0, yellow: 0, red: 0, black: 0, teal: 0};
+        }
+        for tile in factories.iter().flat_map(|factory| factory.iter()) {
+            self.bag.remove(*tile, 1);
+        }
+        self.factories = factories;
+
+        self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(self.bag_zobrist());
+        debug_assert_eq!(self.zobrist, self.compute_zobrist());
         Ok(())
     }
-    fn score(&mut self) -> Result<(), &'static str> {
+    fn score(&mut self) {
+        let before = self.boards_zobrist();
         for board in &mut self.boards {
-            for row in 0..4 {
+            for row in 0..5 {
                 if board.patterns[row].len() == (row + 1) {
                     let color = board.patterns[row].remove(0);
-                    let index = Board::wall_index(color, row)?;
+                    let index = Board::column(color, row);
                     board.wall[row][index] = true;
-                    board.score += board.connected((row, index));
-
-                    match color {
-                        Tile::Start => {return Err("This should never happen")}
-                        Tile::Blue => {self.box_top.blue += (row as u8 + 1)}
-                        Tile::Yellow => {self.box_top.yellow += (row as u8 + 1)}
-                        Tile::Red => {self.box_top.red += (row as u8 + 1)}
-                        Tile::Black => {self.box_top.black += (row as u8 + 1)}
-                        Tile::Teal => {self.box_top.teal += (row as u8 + 1)}
-                    }
+                    board.score += board.connected((row, index)).total() as i16;
+
+                    // One tile goes on the wall, the rest of the line goes in the box
+                    self.box_top.add(color, row as u8);
                     //self.box_top.extend_from_slice(board.patterns[row].as_slice());
                     board.patterns[row].clear();
                 }
             }
-            let negative = match board.floor.len() {
-                0 => 0,
-                1 => 1,
-                2 => 2,
-                3 => 4,
-                4 => 6,
-                5 => 8,
-                6 => 11,
-                _ => 14
-            };
-            board.score -= negative;
+            let negative: i16 = FLOOR_PENALTIES[..board.floor.len()].iter().sum();
+            // Scores can't go below zero
+            board.score = std::cmp::max(board.score - negative, 0);
         }
-        Ok(())
+        self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(self.boards_zobrist());
     }
-    pub fn do_move(&mut self, game_move: GameMove) -> Result<(), &'static str> {
-        let board =  &mut self.boards[self.player as usize];
-        match game_move {
-            GameMove(_, Tile::Start, _) => return Err("You can't take the start tile specifically"),
-            GameMove(0, _, 0) => {
-                if self.market.contains(&game_move.1) {
-                    let mut hand= self.market.clone();
-                    hand.retain(|x| *x == Tile::Start || *x == game_move.1);
-                    self.market.retain(|x| *x != Tile::Start && *x != game_move.1);
-                    
-                    board.floor.extend_from_slice(hand.as_slice());
-                    hand.clear();
+    /// Empties the floors into the box lid, and gives the start tile back to the market.
+    /// Whoever had the start tile begins the next round
+    fn cleanup(&mut self) {
+        let before = self.boards_zobrist().wrapping_add(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+        let mut starting = None;
+        for (i, board) in self.boards.iter_mut().enumerate() {
+            for tile in board.floor.drain(..) {
+                if tile == Tile::Start {
+                    starting = Some(i as u8);
                 }
                 else {
-                    return Err("Market does not contain selected tile")
+                    self.box_top.add(tile, 1);
                 }
+            }
+        }
+
+        match starting {
+            Some(player) => {
+                self.market.push(Tile::Start);
+                self.player = player;
             },
-            GameMove(0, _, 1..=6) => {
-                if self.market.len() == 0 {
-                    return Err("Market is empty");
-                }
-                else if self.market.contains(&game_move.1) {
-                    let target = &mut board.patterns[game_move.2 - 1];
-                    if target.first().is_some() && target[0] != game_move.1 {
-                        return Err("That pattern line already contains a different color")
-                    }
-                    let empty = game_move.2 - target.len();
+            // Nobody took from the market, so the start tile never left it
+            None => self.player = (self.player + 1) % self.boards.len() as u8
+        }
+        let after = self.boards_zobrist().wrapping_add(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+        self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(after);
+    }
+    /// Does a move in place, the move has to be validated against this state.
+    /// A move that ends the round scores the walls, but the factories stay empty,
+    /// only `Game::do_move` starts the next round
+    pub fn do_move(&mut self, valid_move: ValidMove) {
+        self.apply(valid_move);
+    }
+    /// Does a move in place like `do_move`, and returns what's needed to take it back with `unmake_move`
+    pub fn make_move(&mut self, valid_move: ValidMove) -> Undo {
+        // Scoring changes too much to keep track of, and the cheap undo relies on the
+        // factories, market and floors being sorted like `do_move` leaves them
+        if self.ends_round(valid_move.0) || !self.is_sorted() {
+            let before = *self;
+            self.apply(valid_move);
+            return Undo::Full(Box::new(before))
+        }
+        self.apply(valid_move)
+    }
+    pub fn unmake_move(&mut self, undo: Undo) {
+        match undo {
+            Undo::Full(state) => *self = *state,
+            Undo::Move { player, factory, source, color, start, pattern, placed, floored, boxed, bumped } => {
+                let mut before = self.move_zobrist(player as usize, pattern as usize)
+                    .wrapping_add(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+                if source != 0 {
+                    before = before.wrapping_add(Factory::default().zobrist());
+                }
 
-                    if empty == 0 {
-                        return Err("That pattern is full")
+                self.player = player;
+                let board =  &mut self.boards[player as usize];
+    
+                for _ in 0..placed {
+                    board.patterns[pattern as usize - 1].pop();
+                }
+    
+                for _ in 0..floored {
+                    if let Some(i) = board.floor.iter().rposition(|x| *x == color) {
+                        board.floor.remove(i);
                     }
-
-                    let mut hand = self.market.deref().clone();
-                    hand.retain(|x| *x == Tile::Start || *x == game_move.1);
-                    self.market.retain(|x| *x != Tile::Start && *x != game_move.1);
-
-                    for tile in hand.drain(..) {
-                        let empty = game_move.2 - target.len();
-                        if tile == Tile::Start {
-                            board.floor.push(tile);
-                        }
-                        else {
-                            if empty >= 1 {
-                                target.push(tile);
-                            }
-                            else {
-                                board.floor.push(tile)
-                            }
-                        }
+                }
+                if start {
+                    if let Some(i) = board.floor.iter().position(|x| *x == Tile::Start) {
+                        board.floor.remove(i);
                     }
                 }
-                else {
-                    return Err("Market does not contain selected tile")
+                if let Some(tile) = bumped {
+                    self.box_top.remove(tile, 1);
+                    board.floor.push(tile);
                 }
-            },
-            GameMove(1..=9, _, _) => {
-                let board =  &mut self.boards[self.player as usize];
-                if game_move.0 > self.factories.len() {
-                    return Err("That factory is out of bounds");
-                }
-
-                let factory = self.factories[game_move.0 - 1].deref_mut();
-                if factory.contains(&game_move.1) {  
-                    let mut hand = factory.clone();
-                    hand.retain(|x| *x == game_move.1);
-                    factory.retain(|x| *x != game_move.1);
-
-                    self.market.extend_from_slice(factory.as_slice());
-                    factory.clear();
-
-                    match game_move.2 {
-                        0 => {
-                            board.floor.extend_from_slice(hand.as_slice());
-                        },
-                        1..=9 => {
-                            let target = &mut board.patterns[game_move.2 - 1];
-                            if target.first().is_some() && target[0] != game_move.1 {
-                                return Err("That pattern line already contains a different color")
-                            }
-                            let empty = game_move.2 - target.len();
-                            if hand.len() <= empty {
-                                target.extend_from_slice(hand.as_slice());
-                                hand.clear();
-                            }
-                            else if empty != 0 {
-                                for tile in hand.drain(..) {
-                                    let empty = game_move.2 - target.len();
-                                    if empty >= 1 {
-                                        target.push(tile);
-                                    }
-                                    else {
-                                        board.floor.push(tile)
-                                    }
-                                }
-                            }
-                            else {
-                                return Err("That pattern line is full")
-                            }
-                        },
-                        _ => return Err("Not a valid destination")
+                self.box_top.remove(color, boxed);
+                board.floor.sort_unstable();
+    
+                if source == 0 {
+                    let taken = placed + floored + boxed;
+                    for _ in 0..taken {
+                        self.market.push(color);
+                    }
+                    if start {
+                        self.market.push(Tile::Start);
                     }
                 }
                 else {
-                    return Err("That tile is not in that factory")
+                    for tile in factory.iter().filter(|x| **x != color) {
+                        if let Some(i) = self.market.iter().position(|x| x == tile) {
+                            self.market.remove(i);
+                        }
+                    }
+                    if let Some(empty) = self.factories.iter_mut().find(|x| x.is_empty()) {
+                        *empty = factory;
+                    }
+                    self.factories.sort_unstable();
                 }
-            },
-            GameMove(_,_,_) => return Err("Not a valid move")
-        }
+                self.market.sort();
 
-        /*
-        let mut empty = true;
-        for factory in &mut self.factories {
-            if factory.len() != 0 {
-                empty = false;
-                break;
+                let mut after = self.move_zobrist(player as usize, pattern as usize)
+                    .wrapping_add(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+                if source != 0 {
+                    after = after.wrapping_add(factory.zobrist());
+                }
+                self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(after);
+                debug_assert_eq!(self.zobrist, self.compute_zobrist());
             }
         }
-        if empty == true {
-            if self.market.len() != 0 {
-                empty = false;
-            }
+    }
+    fn ends_round(&self, game_move: GameMove) -> bool {
+        let GameMove(source, color, _) = game_move;
+        self.factories.iter().enumerate()
+            .all(|(i, factory)| factory.iter().all(|x| i + 1 == source && *x == color))
+            && self.market.iter().all(|x| *x == Tile::Start || (source == 0 && *x == color))
+    }
+    fn is_sorted(&self) -> bool {
+        self.factories.windows(2).all(|x| x[0] <= x[1])
+            && self.market.windows(2).all(|x| x[0] <= x[1])
+            && self.boards.iter().all(|board| board.floor.windows(2).all(|x| x[0] <= x[1]))
+    }
+    // Can't go wrong for a move validated against this state, the only thing checked
+    fn apply(&mut self, valid_move: ValidMove) -> Undo {
+        debug_assert!(self.validate(valid_move.0).is_ok(), "{:?} was validated against another state", valid_move);
+        let GameMove(source, color, pattern) = valid_move.0;
+        let player = self.player;
+        let mut before = self.move_zobrist(player as usize, pattern);
+        if source != 0 {
+            before = before.wrapping_add(self.factories[source - 1].zobrist());
         }
+        let board =  &mut self.boards[self.player as usize];
 
-        if empty == true {
-            self.score()?;
+        let mut hand = tinyvec::ArrayVec::<[Tile; 28]>::new();
+        let mut factory = Factory::default();
+        if source == 0 {
+            hand.extend(self.market.iter().copied().filter(|x| *x == Tile::Start || *x == color));
+            self.market.retain(|x| *x != Tile::Start && *x != color);
         }
         else {
-            self.player = (self.player + 1) % self.boards.len();
+            factory = self.factories[source - 1];
+            hand.extend(factory.iter().copied().filter(|x| *x == color));
+            self.market.extend(factory.iter().copied().filter(|x| *x != color));
+            self.factories[source - 1].clear();
+        }
+
+        let mut start = false;
+        let mut placed = 0;
+        let mut floored = 0;
+        let mut boxed = 0;
+        let mut bumped = None;
+        for tile in hand {
+            if tile != Tile::Start && pattern != 0 && board.patterns[pattern - 1].len() < pattern {
+                board.patterns[pattern - 1].push(tile);
+                placed += 1;
+            }
+            else {
+                match (tile, Board::add_to_floor(&mut board.floor, tile, &mut self.box_top)) {
+                    (Tile::Start, removed) => {
+                        start = true;
+                        bumped = removed;
+                    },
+                    (_, None) => floored += 1,
+                    (_, Some(_)) => boxed += 1
+                }
+            }
         }
-        */
 
         self.factories.sort_unstable();
         self.market.sort();
@@ -662,81 +1167,297 @@ impl State {
             board.floor.sort_unstable();
         }
 
-        self.player = (self.player + 1) % self.boards.len() as u8;
-        Ok(())
+        let mut after = self.move_zobrist(player as usize, pattern);
+        if source != 0 {
+            after = after.wrapping_add(Factory::default().zobrist());
+        }
+        self.zobrist = self.zobrist.wrapping_sub(before).wrapping_add(after);
+
+        if self.is_round_over() {
+            self.score();
+            self.cleanup();
+        }
+        else {
+            self.zobrist = self.zobrist.wrapping_sub(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+            self.player = (self.player + 1) % self.boards.len() as u8;
+            self.zobrist = self.zobrist.wrapping_add(ZOBRIST[ZOBRIST_PLAYER + self.player as usize]);
+        }
+        debug_assert_eq!(self.zobrist, self.compute_zobrist());
+
+        Undo::Move {
+            player,
+            factory,
+            source: source as u8,
+            color,
+            start,
+            pattern: pattern as u8,
+            placed,
+            floored,
+            boxed,
+            bumped
+        }
     }
-    pub fn is_legal(&self, game_move: GameMove) -> Result<(), &'static str> {
-        let board =  &self.boards[self.player as usize];
-        match game_move {
-            GameMove(_, Tile::Start, _) => return Err("You can't take the start tile specifically"),
-            GameMove(0, _, 0) => {
-                if !&self.market.contains(&game_move.1) {
-                    return Err("Market does not contain selected tile")
-                }
-                else {
-                    Ok(())
+    pub fn players(&self) -> u8 {
+        self.boards.len() as u8
+    }
+    /// The player to move
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+    /// Tiles in the bag by color, blue first
+    pub fn bag(&self) -> [u8; 5] {
+        self.bag.hash()
+    }
+    /// Tiles in the box lid by color, blue first
+    pub fn box_top(&self) -> [u8; 5] {
+        self.box_top.hash()
+    }
+    pub fn factories(&self) -> impl Iterator<Item = &[Tile]> {
+        self.factories.iter().map(|factory| factory.as_slice())
+    }
+    pub fn market(&self) -> &[Tile] {
+        &self.market
+    }
+    /// The score, wall, floor and pattern lines of a player
+    pub fn board(&self, player: usize) -> (i16, [[bool; 5]; 5], &[Tile], [&[Tile]; 5]) {
+        let board = &self.boards[player];
+        let patterns = &board.patterns;
+        (board.score, board.wall, &board.floor, [&patterns[0], &patterns[1], &patterns[2], &patterns[3], &patterns[4]])
+    }
+    /// A position from the same parts the getters give, checked like one read from text
+    pub fn from_parts(player: u8, bag: [u8; 5], box_top: [u8; 5], factories: &[&[Tile]], market: &[Tile],
+                      boards: &[(i16, [[bool; 5]; 5], &[Tile], [&[Tile]; 5])]) -> Result<State, AzulError> {
+        let mut state = State::new(boards.len() as u8)?;
+        state.player = player;
+        state.bag = Bag::from_counts(bag);
+        state.box_top = Bag::from_counts(box_top);
+
+        if factories.len() != state.factories.len() {
+            return Err(AzulError::InvalidPosition("wrong number of factories"));
+        }
+        for (factory, tiles) in state.factories.iter_mut().zip(factories) {
+            factory.0 = fit_tiles(tiles, "factory")?;
+            factory.sort_unstable();
+        }
+        state.factories.sort_unstable();
+        state.market.0 = fit_tiles(market, "market")?;
+        state.market.sort();
+
+        for (board, (score, wall, floor, patterns)) in state.boards.iter_mut().zip(boards) {
+            board.score = *score;
+            board.wall = *wall;
+            board.floor = fit_tiles(floor, "floor")?;
+            board.floor.sort_unstable();
+            for (pattern, tiles) in board.patterns.iter_mut().zip(patterns.iter()) {
+                *pattern = fit_tiles(tiles, "pattern line")?;
+            }
+        }
+
+        state.check()?;
+        state.rehash();
+        Ok(state)
+    }
+    // What every position keeps to, however it was put together
+    fn check(&self) -> Result<(), AzulError> {
+        if self.player >= self.players() {
+            return Err(AzulError::InvalidPosition("player"));
+        }
+        if self.factories.iter().any(|factory| factory.contains(&Tile::Start)) {
+            return Err(AzulError::InvalidPosition("start tile in a factory"));
+        }
+        for board in &self.boards {
+            // The floor never takes a score below zero
+            if board.score < 0 {
+                return Err(AzulError::InvalidPosition("score"));
+            }
+            for (row, pattern) in board.patterns.iter().enumerate() {
+                if pattern.len() > row + 1 || pattern.contains(&Tile::Start)
+                    || pattern.iter().any(|tile| Some(tile) != pattern.first()) {
+                    return Err(AzulError::InvalidPosition("pattern line"));
                 }
-            },
-            GameMove(0, _, 1..=5) => {
-                if self.market.len() == 0 {
-                    return Err("Market is empty");
-                }
-                else if self.market.contains(&game_move.1) {
-                    let target = &board.patterns[game_move.2 - 1];
-                    if target.first().is_some() && target[0] != game_move.1 {
-                        return Err("That pattern line already contains a different color")
-                    }
-                    let empty = game_move.2 - target.len();
+            }
+        }
 
-                    if empty == 0 {
-                        return Err("That pattern is full")
-                    }
+        if self.market.iter().chain(self.boards.iter().flat_map(|board| board.floor.iter()))
+            .filter(|tile| **tile == Tile::Start).count() != 1 {
+            return Err(AzulError::InvalidPosition("there has to be one start tile"));
+        }
 
-                    Ok(())
+        // There are only 20 tiles of every color, and more than that doesn't fit in the counts
+        let mut counts = [0usize; 5];
+        for (count, (bag, box_top)) in counts.iter_mut().zip(self.bag.hash().iter().zip(self.box_top.hash().iter())) {
+            *count += *bag as usize + *box_top as usize;
+        }
+        let tiles = self.factories.iter().flat_map(|factory| factory.iter())
+            .chain(self.market.iter())
+            .chain(self.boards.iter().flat_map(|board| board.floor.iter().chain(board.patterns.iter().flat_map(|x| x.iter()))));
+        for tile in tiles.filter(|tile| **tile != Tile::Start) {
+            counts[*tile as usize - 1] += 1;
+        }
+        for board in &self.boards {
+            for (row, line) in board.wall.iter().enumerate() {
+                for (count, color) in counts.iter_mut().zip(COLORS.iter()) {
+                    if line[Board::column(*color, row)] {
+                        *count += 1;
+                    }
                 }
-                else {
-                    return Err("Market does not contain selected tile")
+            }
+        }
+        if counts.iter().any(|count| *count > 20) {
+            return Err(AzulError::InvalidPosition("more than 20 tiles of a color"));
+        }
+        Ok(())
+    }
+    /// The factory offer phase is over when there are no more tiles to take
+    pub fn is_round_over(&self) -> bool {
+        self.factories.iter().all(|factory| factory.is_empty())
+            && self.market.iter().all(|tile| *tile == Tile::Start)
+    }
+    /// Regular tiles still to be taken this round
+    pub fn tiles_left(&self) -> u8 {
+        let factories = self.factories.iter().map(|factory| factory.len()).sum::<usize>();
+        let market = self.market.iter().filter(|tile| **tile != Tile::Start).count();
+        (factories + market) as u8
+    }
+    /// The game ends after the round where someone completes a horizontal row
+    pub fn is_over(&self) -> bool {
+        self.is_round_over() && self.boards.iter().any(|board| board.has_full_row())
+    }
+    pub fn final_scores(&self) -> Result<Vec<i16>, AzulError> {
+        if !self.is_over() {
+            return Err(AzulError::GameNotOver)
+        }
+        let mut scores = Vec::with_capacity(self.boards.len());
+        for board in &self.boards {
+            scores.push(board.score + board.bonus()? as i16);
+        }
+        Ok(scores)
+    }
+    /// Checks a move against the rules, this is the only way to get a move `do_move` accepts
+    pub fn validate(&self, game_move: GameMove) -> Result<ValidMove, AzulError> {
+        let board =  &self.boards[self.player as usize];
+        let GameMove(source, color, pattern) = game_move;
+
+        if color == Tile::Start {
+            return Err(AzulError::TakeStartTile)
+        }
+
+        if source == 0 {
+            if self.market.is_empty() {
+                return Err(AzulError::MarketEmpty)
+            }
+            else if !self.market.contains(&color) {
+                return Err(AzulError::NotInMarket(color))
+            }
+        }
+        else if source > self.factories.len() {
+            return Err(AzulError::FactoryOutOfBounds(source))
+        }
+        else if !self.factories[source - 1].contains(&color) {
+            return Err(AzulError::NotInFactory { factory: source, color })
+        }
+
+        board.check_pattern(color, pattern)?;
+
+        Ok(ValidMove(game_move))
+    }
+    /// Every legal move for the current player, straight from what is on offer
+    pub fn legal_moves(&self) -> LegalMoves {
+        let board =  &self.boards[self.player as usize];
+        let mut moves = LegalMoves::new();
+
+        for color in COLORS.iter() {
+            // Where the color can go doesn't depend on where it's taken from
+            let mut patterns = [false; 6];
+            for pattern in 0..6 {
+                patterns[pattern] = board.check_pattern(*color, pattern).is_ok();
+            }
+
+            for source in 0..=self.factories.len() {
+                let tiles = match source {
+                    0 => self.market.as_slice(),
+                    _ => self.factories[source - 1].as_slice()
+                };
+                if !tiles.contains(color) {
+                    continue;
                 }
-            },
-            GameMove(1..=9, _, _) => {
-                let board =  &self.boards[self.player as usize];
-                if game_move.0 > self.factories.len() {
-                    return Err("That factory is out of bounds");
-                }
-
-                let factory = self.factories[game_move.0 - 1].deref();
-                if factory.contains(&game_move.1) {  
-                    let mut hand = factory.clone();
-                    hand.retain(|x| *x == game_move.1);
-
-                    match game_move.2 {
-                        0 => Ok(()),
-                        1..=9 => {
-                            let target = &board.patterns[game_move.2 - 1];
-                            if target.first().is_some() && target[0] != game_move.1 {
-                                return Err("That pattern line already contains a different color")
-                            }
-                            let empty = game_move.2 - target.len();
-                            if hand.len() <= empty {
-                                return Ok(())
-                            }
-                            else if empty != 0 {
-                                return Ok(())
-                            }
-                            else {
-                                return Err("That pattern line is full")
-                            }
-                        },
-                        _ => return Err("Not a valid destination")
+                for pattern in 0..6 {
+                    if patterns[pattern] {
+                        moves.push(ValidMove(GameMove(source, *color, pattern)));
                     }
                 }
-                else {
-                    return Err("That tile is not in that factory")
-                }
-            },
-            GameMove(_,_,_) => return Err("Not a valid move")
+            }
+        }
+        moves
+    }
+    pub fn is_legal(&self, game_move: GameMove) -> Result<(), AzulError> {
+        self.validate(game_move).map(|_| ())
+    }
+    /// The state with its symmetries folded away: the seats are turned so the player to move
+    /// comes first, and the colours are relabelled as long as no wall can tell them apart.
+    /// Symmetric states have the same canonical form, and the same moves until the end of the round.
+    /// Not past it though, the relabelled colors go to other wall columns once they're scored
+    pub fn canonical(&self) -> State {
+        let mut canonical = self.relabel(&COLORS);
+        for colors in color_permutations() {
+            if !self.keeps_walls(colors) {
+                continue;
+            }
+            let state = self.relabel(colors);
+            if state.zobrist < canonical.zobrist {
+                canonical = state;
+            }
+        }
+        canonical
+    }
+    // Once a color is on a wall it plays differently from the others, so relabelling
+    // only works when every wall row ends up with the same colors
+    fn keeps_walls(&self, colors: &[Tile; 5]) -> bool {
+        self.boards.iter().all(|board| (0..5).all(|row| {
+            COLORS.iter().zip(colors.iter()).all(|(from, to)| {
+                board.wall[row][(*from as usize - 1 + row) % 5] == board.wall[row][(*to as usize - 1 + row) % 5]
+            })
+        }))
+    }
+    /// `colors[i]` takes the place of `COLORS[i]`, and the player to move takes the first seat
+    pub(crate) fn relabel(&self, colors: &[Tile; 5]) -> State {
+        let map = |tile: Tile| match tile {
+            Tile::Start => Tile::Start,
+            color => colors[color as usize - 1]
+        };
+        let mut state = *self;
+
+        state.bag = Bag { blue: 0, yellow: 0, red: 0, black: 0, teal: 0 };
+        state.box_top = state.bag;
+        for (color, (bag, box_top)) in colors.iter().zip(self.bag.hash().iter().zip(self.box_top.hash().iter())) {
+            state.bag.add(*color, *bag);
+            state.box_top.add(*color, *box_top);
         }
 
+        for factory in &mut state.factories {
+            for tile in factory.iter_mut() {
+                *tile = map(*tile);
+            }
+            factory.sort_unstable();
+        }
+        state.factories.sort_unstable();
+        for tile in state.market.iter_mut() {
+            *tile = map(*tile);
+        }
+        state.market.sort();
+
+        let players = self.boards.len();
+        for (i, board) in state.boards.iter_mut().enumerate() {
+            *board = self.boards[(i + self.player as usize) % players];
+            for tile in board.floor.iter_mut().chain(board.patterns.iter_mut().flat_map(|x| x.iter_mut())) {
+                *tile = map(*tile);
+            }
+            board.floor.sort_unstable();
+        }
+        state.player = 0;
+
+        state.rehash();
+        state
     }
     /*pub fn hash(&self) -> [u8; 256]{
         [
@@ -747,24 +1468,267 @@ impl State {
     }*/
 }
 
+// Tiles as letters, with - for none
+fn write_tiles(f: &mut std::fmt::Formatter, tiles: &[Tile]) -> std::fmt::Result {
+    if tiles.is_empty() {
+        return write!(f, "-");
+    }
+    for tile in tiles {
+        write!(f, "{}", tile.letter())?;
+    }
+    Ok(())
+}
+
+fn read_tiles<A: tinyvec::Array<Item = Tile>>(text: &str, error: &'static str) -> Result<tinyvec::ArrayVec<A>, AzulError> {
+    let mut tiles = tinyvec::ArrayVec::new();
+    if text == "-" {
+        return Ok(tiles);
+    }
+    for letter in text.chars() {
+        let tile = Tile::from_letter(letter).ok_or(AzulError::InvalidPosition(error))?;
+        tiles.try_push(tile).map_or(Ok(()), |_| Err(AzulError::InvalidPosition(error)))?;
+    }
+    Ok(tiles)
+}
+
+fn fit_tiles<A: tinyvec::Array<Item = Tile>>(tiles: &[Tile], error: &'static str) -> Result<tinyvec::ArrayVec<A>, AzulError> {
+    let mut fitted = tinyvec::ArrayVec::new();
+    for tile in tiles {
+        fitted.try_push(*tile).map_or(Ok(()), |_| Err(AzulError::InvalidPosition(error)))?;
+    }
+    Ok(fitted)
+}
+
+fn read_counts(text: &str, error: &'static str) -> Result<Bag, AzulError> {
+    let counts = text.split(',')
+        .map(|x| x.parse::<u8>().map_err(|_| AzulError::InvalidPosition(error)))
+        .collect::<Result<Vec<u8>, AzulError>>()?;
+    match counts.as_slice() {
+        [blue, yellow, red, black, teal] => Ok(Bag { blue: *blue, yellow: *yellow, red: *red, black: *black, teal: *teal }),
+        _ => Err(AzulError::InvalidPosition(error))
+    }
+}
+
+/// A position on one line: the player to move, the bag and the box lid as counts of every color,
+/// the factories split by dots, the market, and then every board as score, wall, floor and pattern lines.
+/// Tiles are written as letters, so a fresh two player game looks like
+/// `0 20,20,20,20,20 0,0,0,0,0 -.-.-.-.- S 0:...../...../...../...../.....:-:-/-/-/-/- 0:...`
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [blue, yellow, red, black, teal] = self.bag.hash();
+        write!(f, "{} {},{},{},{},{} ", self.player, blue, yellow, red, black, teal)?;
+        let [blue, yellow, red, black, teal] = self.box_top.hash();
+        write!(f, "{},{},{},{},{} ", blue, yellow, red, black, teal)?;
+
+        for (i, factory) in self.factories.iter().enumerate() {
+            if i != 0 {
+                write!(f, ".")?;
+            }
+            write_tiles(f, factory)?;
+        }
+        write!(f, " ")?;
+        write_tiles(f, &self.market)?;
+
+        for board in &self.boards {
+            write!(f, " {}:", board.score)?;
+            for (i, row) in board.wall.iter().enumerate() {
+                if i != 0 {
+                    write!(f, "/")?;
+                }
+                for taken in row {
+                    write!(f, "{}", if *taken { 'x' } else { '.' })?;
+                }
+            }
+            write!(f, ":")?;
+            write_tiles(f, &board.floor)?;
+            write!(f, ":")?;
+            for (i, pattern) in board.patterns.iter().enumerate() {
+                if i != 0 {
+                    write!(f, "/")?;
+                }
+                write_tiles(f, pattern)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for State {
+    type Err = AzulError;
+
+    fn from_str(text: &str) -> Result<State, AzulError> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() < 5 {
+            return Err(AzulError::InvalidPosition("missing fields"));
+        }
+        let mut state = State::new((fields.len() - 5) as u8)?;
+
+        state.player = fields[0].parse().map_err(|_| AzulError::InvalidPosition("player"))?;
+        state.bag = read_counts(fields[1], "bag")?;
+        state.box_top = read_counts(fields[2], "box lid")?;
+
+        let factories: Vec<&str> = fields[3].split('.').collect();
+        if factories.len() != state.factories.len() {
+            return Err(AzulError::InvalidPosition("wrong number of factories"));
+        }
+        for (factory, text) in state.factories.iter_mut().zip(factories) {
+            factory.0 = read_tiles(text, "factory")?;
+            factory.sort_unstable();
+        }
+        state.factories.sort_unstable();
+        state.market.0 = read_tiles(fields[4], "market")?;
+        state.market.sort();
+
+        for (board, text) in state.boards.iter_mut().zip(&fields[5..]) {
+            let parts: Vec<&str> = text.split(':').collect();
+            let (score, wall, floor, patterns) = match parts.as_slice() {
+                [score, wall, floor, patterns] => (score, wall, floor, patterns),
+                _ => return Err(AzulError::InvalidPosition("board"))
+            };
+
+            board.score = score.parse().map_err(|_| AzulError::InvalidPosition("score"))?;
+
+            let rows: Vec<&str> = wall.split('/').collect();
+            if rows.len() != 5 || rows.iter().any(|row| row.len() != 5) {
+                return Err(AzulError::InvalidPosition("wall"));
+            }
+            for (row, text) in board.wall.iter_mut().zip(rows) {
+                for (taken, letter) in row.iter_mut().zip(text.chars()) {
+                    *taken = match letter {
+                        'x' => true,
+                        '.' => false,
+                        _ => return Err(AzulError::InvalidPosition("wall"))
+                    };
+                }
+            }
+
+            board.floor = read_tiles(floor, "floor")?;
+            board.floor.sort_unstable();
+
+            let lines: Vec<&str> = patterns.split('/').collect();
+            if lines.len() != 5 {
+                return Err(AzulError::InvalidPosition("pattern lines"));
+            }
+            for (pattern, text) in board.patterns.iter_mut().zip(lines) {
+                *pattern = read_tiles(text, "pattern line")?;
+            }
+        }
+
+        state.check()?;
+        state.rehash();
+        Ok(state)
+    }
+}
+
+/// Something that happened in a game
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Move(GameMove),
+    /// The factories as they were drawn from the bag
+    Fill(Factories),
+}
+
 //#[repr(align(16))]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Game {
     pub state: State,
     pub turn: u32,
+    // Every fill draws from here, so the same seed plays out the same game
+    rng: StdRng,
+    // Everything up to the length of `undos` has been done, the rest can be redone
+    history: Vec<Event>,
+    undos: Vec<Undo>,
 }
 impl Game {
-    pub fn new(players: u8) -> Result<Game, &'static str> {
+    /// The factories of every round are drawn with random numbers from `seed`
+    pub fn new(players: u8, seed: u64) -> Result<Game, AzulError> {
         let game = Game {
             state: State::new(players)?,
-            turn: 0
+            turn: 0,
+            rng: StdRng::seed_from_u64(seed),
+            history: Vec::new(),
+            undos: Vec::new()
         };
         Ok(game)
     }
-    pub fn do_move(&mut self, game_move: GameMove) -> Result<(), &'static str> {
-        let result = self.state.do_move(game_move);
+    /// Fills the factories for the first round, the later ones are filled by `do_move`
+    pub fn fill(&mut self) -> Result<(), AzulError> {
+        let mut rng = self.rng.clone();
+        let before = self.state;
+        self.state.fill(StdRng::seed_from_u64(rng.gen()))?;
+        self.rng = rng;
+        self.record(Event::Fill(self.state.factories), Undo::Full(Box::new(before)));
+        Ok(())
+    }
+    /// Does a move, and refills the factories when it ends the round.
+    /// Nothing changes if the refill fails
+    pub fn do_move(&mut self, valid_move: ValidMove) -> Result<(), AzulError> {
+        let mut state = self.state;
+        let undo = state.make_move(valid_move);
+        let mut fill = None;
+        if state.is_round_over() && !state.is_over() {
+            let mut rng = self.rng.clone();
+            let before = state;
+            state.fill(StdRng::seed_from_u64(rng.gen()))?;
+            fill = Some((Event::Fill(state.factories), Undo::Full(Box::new(before))));
+            self.rng = rng;
+        }
+        self.state = state;
         self.turn += 1;
-        result
+
+        self.record(Event::Move(valid_move.game_move()), undo);
+        if let Some((event, undo)) = fill {
+            self.record(event, undo);
+        }
+        Ok(())
+    }
+    fn record(&mut self, event: Event, undo: Undo) {
+        // Anything that could have been redone is a different line now
+        self.history.truncate(self.undos.len());
+        self.history.push(event);
+        self.undos.push(undo);
+    }
+    /// Everything that has happened so far, leaving out what has been undone
+    pub fn history(&self) -> &[Event] {
+        &self.history[..self.undos.len()]
+    }
+    /// Takes back the last move, and the fill it led to. Returns false if there are no moves
+    pub fn undo(&mut self) -> bool {
+        if !self.history().iter().any(|event| matches!(event, Event::Move(_))) {
+            return false
+        }
+        while let Some(undo) = self.undos.pop() {
+            self.state.unmake_move(undo);
+            if let Event::Move(_) = self.history[self.undos.len()] {
+                self.turn -= 1;
+                break;
+            }
+        }
+        true
+    }
+    /// Does the next undone move again, and the fill it led to. Returns false if there's nothing to redo
+    pub fn redo(&mut self) -> Result<bool, AzulError> {
+        let mut moved = false;
+        while let Some(event) = self.history.get(self.undos.len()).cloned() {
+            match event {
+                Event::Move(game_move) => {
+                    if moved {
+                        break;
+                    }
+                    let valid_move = self.state.validate(game_move)?;
+                    let undo = self.state.make_move(valid_move);
+                    self.undos.push(undo);
+                    self.turn += 1;
+                    moved = true;
+                },
+                Event::Fill(factories) => {
+                    let before = self.state;
+                    self.state.refill(factories)?;
+                    self.undos.push(Undo::Full(Box::new(before)));
+                }
+            }
+        }
+        Ok(moved)
     }
 }
 impl Deref for Game {
@@ -782,23 +1746,26 @@ impl DerefMut for Game {
 
 // Tests
 
-pub fn complicated() -> Result<Game, &'static str> {
-    let mut game = Game::new(2)?;
+pub fn complicated() -> Result<Game, AzulError> {
+    let mut game = Game::new(2, 0)?;
 
     let mut tiles = Tile::Blue;
 
-    for factory in &mut game.factories {
+    let state = &mut game.state;
+    for factory in &mut state.factories {
         for _ in 0..4 {
             factory.push(tiles);
+            state.bag.remove(tiles, 1);
             tiles = tiles.into_iter().next().unwrap_or(Tile::Blue);
         }
     }
+    game.rehash();
 
     Ok(game)
 }
 
-pub fn some_game() -> Result<Game, &'static str> {
-    let mut game = Game::new(2)?;
+pub fn some_game() -> Result<Game, AzulError> {
+    let mut game = Game::new(2, 0)?;
 
     let factories = &mut game.factories;
 
@@ -833,12 +1800,13 @@ pub fn some_game() -> Result<Game, &'static str> {
     bag.black -= 4;
     bag.red -= 5;
     bag.teal -= 3;
+    game.rehash();
 
     Ok(game)
 }
 
 #[test]
-fn connected() -> Result<(), String> {
+fn connected() -> Result<(), AzulError> {
     let mut board = Board::default();
     board.wall[0] = [false, false, false, false, false];
     board.wall[1] = [true,  false, true,  false, false];
@@ -851,7 +1819,484 @@ fn connected() -> Result<(), String> {
 
     let score = board.connected(coordinate);
 
-    assert_eq!(score, 7);
+    assert_eq!(score, Connected { row: 3, column: 4 });
+    assert_eq!(score.total(), 7);
+
+    // Alone
+    board.wall[0][4] = true;
+    let score = board.connected((0, 4));
+    assert_eq!(score, Connected { row: 1, column: 0 });
+
+    // Only horizontal neighbours
+    board.wall[0] = [true,  true,  false, false, false];
+    let score = board.connected((0, 1));
+    assert_eq!(score, Connected { row: 2, column: 0 });
+
+    // Only vertical neighbours
+    let score = board.connected((2, 2));
+    assert_eq!(score, Connected { row: 0, column: 2 });
+    Ok(())
+}
+
+#[test]
+fn same_seed() -> Result<(), AzulError> {
+    // Every round, not just the first, is drawn from the seed
+    let mut games = [Game::new(2, 9)?, Game::new(2, 9)?];
+    let mut rng = StdRng::seed_from_u64(9);
+    for game in &mut games {
+        game.fill()?;
+    }
+    while !games[0].is_over() {
+        let moves = games[0].legal_moves();
+        let game_move = moves[rng.gen_range(0..moves.len())].game_move();
+        for game in &mut games {
+            game.do_move(game.validate(game_move)?)?;
+        }
+        assert_eq!(games[0].state, games[1].state);
+    }
+    assert!(games[0].history().iter().filter(|event| matches!(event, Event::Fill(_))).count() > 1);
+    Ok(())
+}
+
+#[test]
+fn round_end() -> Result<(), AzulError> {
+    let mut game = Game::new(2, 42)?;
+    game.fill()?;
+
+    let mut moves = 0;
+    while !game.boards.iter().any(|board| board.wall.iter().flatten().any(|x| *x)) {
+        let game_move = GameMove::default().into_iter()
+            .find_map(|x| game.validate(x).ok())
+            .ok_or(AzulError::NoLegalMoves)?;
+        game.do_move(game_move)?;
+        moves += 1;
+        assert!(moves < 100);
+    }
+
+    assert!(!game.is_round_over());
+    assert_eq!(game.factories.iter().map(|x| x.len()).sum::<usize>(), 20);
+    assert_eq!(game.bag.len(), 100 - 40);
+    Ok(())
+}
+
+#[test]
+fn final_scores() -> Result<(), AzulError> {
+    let mut game = Game::new(2, 0)?;
+    assert!(!game.is_over());
+    assert!(game.final_scores().is_err());
+
+    // No tiles left to take, and a full row on the first board
+    game.market.clear();
+    let board = &mut game.boards[0];
+    board.score = 20;
+    board.wall[0] = [true,  true,  true,  true,  true];
+    board.wall[1] = [true,  true,  false, false, false];
+    board.wall[2] = [true,  false, true,  false, false];
+    board.wall[3] = [true,  false, false, true,  false];
+    board.wall[4] = [true,  false, false, false, true];
+    assert!(game.is_over());
+
+    // One row, one column and one color (blue)
+    assert_eq!(game.final_scores()?, vec![20 + 2 + 7 + 10, 0]);
+    Ok(())
+}
+
+#[test]
+fn floor_penalty() -> Result<(), AzulError> {
+    let mut state = State::new(2)?;
+    state.market.clear();
+    state.market.extend_from_slice(&[Tile::Start, Tile::Red, Tile::Red, Tile::Red]);
+    state.factories[3].extend_from_slice(&[Tile::Blue, Tile::Blue, Tile::Blue, Tile::Blue]);
+    state.factories[4].extend_from_slice(&[Tile::Blue, Tile::Blue, Tile::Blue, Tile::Yellow]);
+    state.boards[0].score = 3;
+    state.rehash();
+
+    // 7 tiles to the floor
+    state.do_move(state.validate(GameMove(4, Tile::Blue, 0))?);
+    state.player = 0;
+    state.rehash();
+    state.do_move(state.validate(GameMove(5, Tile::Blue, 0))?);
+    state.player = 0;
+    state.rehash();
+    assert_eq!(state.boards[0].floor.len(), 7);
+
+    // The start tile bumps a regular tile off, the rest go to the box lid
+    state.do_move(state.validate(GameMove(0, Tile::Red, 0))?);
+    state.player = 0;
+    state.rehash();
+    assert_eq!(state.boards[0].floor.len(), 7);
+    assert_eq!(state.boards[0].floor[0], Tile::Start);
+    assert_eq!(state.box_top.len(), 4);
+
+    // The yellow tile, and the 6 regular tiles from the floor
+    state.do_move(state.validate(GameMove(0, Tile::Yellow, 0))?);
+    assert_eq!(state.box_top.len(), 11);
+
+    // The round ended, the score doesn't go below zero
+    assert!(state.is_round_over());
+    assert_eq!(state.boards[0].score, 0);
+    assert_eq!(state.boards[1].score, 0);
+    Ok(())
+}
+
+#[test]
+fn round_cleanup() -> Result<(), AzulError> {
+    let mut state = State::new(2)?;
+    state.factories[3].extend_from_slice(&[Tile::Blue, Tile::Blue, Tile::Blue, Tile::Yellow]);
+    state.factories[4].extend_from_slice(&[Tile::Red, Tile::Red, Tile::Red, Tile::Red]);
+    state.rehash();
+
+    state.do_move(state.validate(GameMove(4, Tile::Blue, 3))?);
+    state.do_move(state.validate(GameMove(0, Tile::Yellow, 1))?);
+    state.do_move(state.validate(GameMove(5, Tile::Red, 0))?);
+
+    assert!(state.is_round_over());
+    assert!(state.boards.iter().all(|board| board.floor.is_empty()));
+    assert_eq!(state.market.as_slice(), &[Tile::Start]);
+    // Two blue tiles from the pattern line, and four red tiles from the floor
+    assert_eq!(state.box_top.len(), 6);
+    // Player two took the start tile
+    assert_eq!(state.player, 1);
+    Ok(())
+}
+
+#[test]
+fn player_counts() -> Result<(), AzulError> {
+    assert!(State::new(1).is_err());
+    assert!(State::new(5).is_err());
+
+    for players in 2..=4 {
+        let mut game = Game::new(players, players as u64)?;
+        game.fill()?;
+
+        let n_factories = get_n_factories(players)? as usize;
+        assert_eq!(game.players(), players);
+        assert_eq!(game.factories.len(), n_factories);
+        assert!(game.factories.iter().all(|factory| factory.len() == 4));
+
+        // Play the first round, everyone should get a turn
+        let mut moves = 0;
+        let mut seen = [false; 4];
+        while game.bag.len() as usize == 100 - 4 * n_factories {
+            seen[game.player as usize] = true;
+            let game_move = GameMoveIter::new(players)
+                .find_map(|x| game.validate(x).ok())
+                .ok_or(AzulError::NoLegalMoves)?;
+            game.do_move(game_move)?;
+            moves += 1;
+            assert!(moves < 100);
+        }
+
+        assert_eq!(seen.iter().filter(|x| **x).count(), players as usize);
+        assert!(game.factories.iter().all(|factory| factory.len() == 4));
+        assert_eq!(game.bag.len() as usize, 100 - 2 * 4 * n_factories);
+    }
+    Ok(())
+}
+
+#[test]
+fn wall_blocks_pattern() -> Result<(), AzulError> {
+    let mut state = some_game()?.state;
+    state.market.push(Tile::Red);
+    // Red goes in the fourth column of the second row
+    state.boards[0].wall[1][3] = true;
+    state.rehash();
+
+    let blocked = Err(AzulError::WallTaken { row: 1, column: 3, color: Tile::Red });
+    assert_eq!(state.is_legal(GameMove(2, Tile::Red, 2)), blocked);
+    assert_eq!(state.is_legal(GameMove(0, Tile::Red, 2)), blocked);
+
+    // Other lines, and the floor, are still fine
+    assert_eq!(state.is_legal(GameMove(2, Tile::Red, 3)), Ok(()));
+    assert_eq!(state.is_legal(GameMove(2, Tile::Red, 0)), Ok(()));
+    state.do_move(state.validate(GameMove(2, Tile::Red, 0))?);
+    Ok(())
+}
+
+#[test]
+fn validate() -> Result<(), AzulError> {
+    let mut state = some_game()?.state;
+    state.market.push(Tile::Red);
+    state.rehash();
+
+    // There are only 5 pattern lines
+    assert_eq!(state.is_legal(GameMove(0, Tile::Red, 6)), Err(AzulError::InvalidPattern(6)));
+    assert_eq!(state.is_legal(GameMove(2, Tile::Red, 6)), Err(AzulError::InvalidPattern(6)));
+    assert_eq!(state.is_legal(GameMove(6, Tile::Red, 1)), Err(AzulError::FactoryOutOfBounds(6)));
+
+    // Three red tiles on a line of two, one goes to the floor
+    let valid_move = state.validate(GameMove(2, Tile::Red, 2))?;
+    assert_eq!(valid_move.game_move(), GameMove(2, Tile::Red, 2));
+    state.do_move(valid_move);
+    assert_eq!(state.boards[0].patterns[1].as_slice(), &[Tile::Red, Tile::Red]);
+    assert_eq!(state.boards[0].floor.as_slice(), &[Tile::Red]);
+    assert_eq!(state.market.as_slice(), &[Tile::Start, Tile::Blue, Tile::Red]);
+    Ok(())
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "was validated against another state")]
+fn move_from_another_state() {
+    let mut state = State::new(2).unwrap();
+    state.factories[4].extend_from_slice(&[Tile::Red, Tile::Red, Tile::Red, Tile::Red]);
+    state.rehash();
+    let valid_move = state.validate(GameMove(5, Tile::Red, 4)).unwrap();
+
+    // Validated against another state, which has no tiles in its factories yet
+    State::new(2).unwrap().do_move(valid_move);
+}
+
+/// Every position of a game played with random moves from `seed`, until it's over
+/// or the bag and the box lid run out
+#[cfg(test)]
+fn random_game(players: u8, seed: u64) -> Result<Vec<State>, AzulError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut game = Game::new(players, seed)?;
+    game.fill()?;
+    let mut states = vec![game.state];
+    while !game.is_over() {
+        let moves = game.legal_moves();
+        if moves.is_empty() {
+            break;
+        }
+        game.do_move(moves[rng.gen_range(0..moves.len())])?;
+        states.push(game.state);
+    }
+    Ok(states)
+}
+
+#[test]
+fn legal_moves() -> Result<(), AzulError> {
+    for players in 2..=4 {
+        for state in random_game(players, players as u64)? {
+            let moves = state.legal_moves();
+
+            let mut expected = 0;
+            for source in 0..=state.factories.len() {
+                for color in COLORS.iter() {
+                    for pattern in 0..=6 {
+                        if let Ok(valid_move) = state.validate(GameMove(source, *color, pattern)) {
+                            assert!(moves.contains(&valid_move));
+                            expected += 1;
+                        }
+                    }
+                }
+            }
+            assert_eq!(moves.len(), expected);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn make_unmake_move() -> Result<(), AzulError> {
+    let mut cheap = 0;
+    for seed in 0..20 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for before in random_game(2 + (seed % 3) as u8, seed)? {
+            // Every single move can be taken back
+            for valid_move in before.legal_moves() {
+                let mut state = before;
+                let undo = state.make_move(valid_move);
+                if let Undo::Move { .. } = undo {
+                    cheap += 1;
+                }
+                let mut after = before;
+                after.do_move(valid_move);
+                assert_eq!(state, after);
+
+                state.unmake_move(undo);
+                assert_eq!(state, before);
+            }
+
+            // And so can a whole line of them
+            let mut state = before;
+            let mut undos = Vec::new();
+            for _ in 0..4 {
+                let moves = state.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                undos.push(state.make_move(moves[rng.gen_range(0..moves.len())]));
+            }
+            while let Some(undo) = undos.pop() {
+                state.unmake_move(undo);
+            }
+            assert_eq!(state, before);
+        }
+    }
+    assert!(cheap > 0);
+    Ok(())
+}
+
+#[test]
+fn history() -> Result<(), AzulError> {
+    let mut rng = StdRng::seed_from_u64(13);
+    let mut game = Game::new(2, 13)?;
+    game.fill()?;
+    assert!(!game.undo());
+    assert_eq!(game.redo()?, false);
+
+    // Through a couple of rounds
+    let mut states = vec![game.state];
+    while game.history().iter().filter(|event| matches!(event, Event::Fill(_))).count() < 3 {
+        let moves = game.legal_moves();
+        game.do_move(moves[rng.gen_range(0..moves.len())])?;
+        states.push(game.state);
+    }
+    let history = game.history().to_vec();
+    assert_eq!(history.len(), states.len() - 1 + 3);
+    assert_eq!(game.turn as usize, states.len() - 1);
+
+    for state in states.iter().rev().skip(1) {
+        assert!(game.undo());
+        assert_eq!(&game.state, state);
+    }
+    assert!(!game.undo());
+    assert_eq!(game.history().len(), 1);
+    assert_eq!(game.turn, 0);
+
+    for state in states.iter().skip(1) {
+        assert_eq!(game.redo()?, true);
+        assert_eq!(&game.state, state);
+    }
+    assert_eq!(game.redo()?, false);
+    assert_eq!(game.history(), history.as_slice());
+
+    // Going down another line forgets what could have been redone
+    game.undo();
+    game.undo();
+    let moves = game.legal_moves();
+    game.do_move(moves[0])?;
+    assert_eq!(game.redo()?, false);
+    assert_eq!(game.turn as usize, states.len() - 2);
+    Ok(())
+}
+
+#[test]
+fn zobrist() -> Result<(), AzulError> {
+    let mut start = State::new(2)?;
+    for (factory, color) in [Tile::Blue, Tile::Yellow, Tile::Red, Tile::Black].iter().enumerate() {
+        start.factories[factory + 1].extend_from_slice(&[*color; 4]);
+    }
+    start.rehash();
+
+    let take = |state: &mut State, color: Tile, pattern: usize| -> Result<(), AzulError> {
+        let source = state.factories.iter().position(|x| x.first() == Some(&color)).unwrap() + 1;
+        state.do_move(state.validate(GameMove(source, color, pattern))?);
+        Ok(())
+    };
+
+    // The same position through two different orders
+    let mut a = start;
+    take(&mut a, Tile::Blue, 4)?;
+    take(&mut a, Tile::Red, 0)?;
+    take(&mut a, Tile::Yellow, 5)?;
+    let mut b = start;
+    take(&mut b, Tile::Yellow, 5)?;
+    take(&mut b, Tile::Red, 0)?;
+    take(&mut b, Tile::Blue, 4)?;
+    assert_eq!(a, b);
+    assert_eq!(a.zobrist(), b.zobrist());
+    assert_ne!(a.zobrist(), start.zobrist());
+
+    // Different positions get different hashes
+    let mut seen = std::collections::HashMap::new();
+    for seed in 0..10 {
+        for state in random_game(2 + (seed % 3) as u8, seed)? {
+            assert_eq!(state.zobrist(), state.compute_zobrist());
+            assert_eq!(*seen.entry(state.zobrist()).or_insert(state), state);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn canonical() -> Result<(), AzulError> {
+    let mut game = random_game(3, 20)?[4];
+
+    // Four moves in, nothing on the walls yet, so every relabelling plays the same
+    let canonical = game.canonical();
+    assert_eq!(color_permutations().len(), 120);
+    for colors in color_permutations() {
+        assert!(game.keeps_walls(colors));
+        let state = game.relabel(colors);
+        assert_eq!(state.zobrist(), state.compute_zobrist());
+        assert_eq!(state.legal_moves().len(), game.legal_moves().len());
+        assert_eq!(state.canonical(), canonical);
+    }
+
+    // Turning the seats around
+    let mut turned = game;
+    turned.boards.rotate_right(1);
+    turned.player = (turned.player + 1) % 3;
+    turned.rehash();
+    assert_eq!(turned.canonical(), canonical);
+
+    // Blue on the wall sets it apart from the other colors
+    game.boards[0].wall[0][0] = true;
+    game.rehash();
+    let blue_yellow = [Tile::Yellow, Tile::Blue, Tile::Red, Tile::Black, Tile::Teal];
+    let yellow_red = [Tile::Blue, Tile::Red, Tile::Yellow, Tile::Black, Tile::Teal];
+    assert!(!game.keeps_walls(&blue_yellow));
+    assert!(game.keeps_walls(&yellow_red));
+    assert_eq!(game.relabel(&yellow_red).canonical(), game.canonical());
+    assert_eq!(color_permutations().iter().filter(|colors| game.keeps_walls(colors)).count(), 24);
+    Ok(())
+}
+
+#[test]
+fn position_text() -> Result<(), AzulError> {
+    let state = State::new(2)?;
+    assert_eq!(state.to_string(),
+        "0 20,20,20,20,20 0,0,0,0,0 -.-.-.-.- S 0:...../...../...../...../.....:-:-/-/-/-/- 0:...../...../...../...../.....:-:-/-/-/-/-");
+
+    for players in 2..=4 {
+        for position in random_game(players, players as u64)? {
+            let text = position.to_string();
+            let state: State = text.parse()?;
+            assert_eq!(state, position, "{}", text);
+            assert_eq!(state.zobrist(), position.zobrist());
+        }
+    }
+
+    let text = state.to_string();
+    assert!("".parse::<State>().is_err());
+    assert_eq!(text.replacen("-.-.-.-.-", "-.-.-.-", 1).parse::<State>(), Err(AzulError::InvalidPosition("wrong number of factories")));
+    assert_eq!(text.replacen(" S ", " - ", 1).parse::<State>(), Err(AzulError::InvalidPosition("there has to be one start tile")));
+    assert_eq!(text.replacen("-/-/-/-/-", "BY/-/-/-/-", 1).parse::<State>(), Err(AzulError::InvalidPosition("pattern line")));
+    assert_eq!(text.replacen(".....:", "..o..:", 1).parse::<State>(), Err(AzulError::InvalidPosition("wall")));
+    assert_eq!(text.replacen("0 ", "2 ", 1).parse::<State>(), Err(AzulError::InvalidPosition("player")));
+    assert_eq!(text.replacen("S 0:", "S -3:", 1).parse::<State>(), Err(AzulError::InvalidPosition("score")));
+    let too_many = Err(AzulError::InvalidPosition("more than 20 tiles of a color"));
+    assert_eq!(text.replacen("20,20,20,20,20", "200,200,200,200,200", 1).parse::<State>(), too_many);
+    assert_eq!(text.replacen("0,0,0,0,0", "0,0,1,0,0", 1).parse::<State>(), too_many);
+    assert_eq!(text.replacen("-.-.-.-.-", "R.-.-.-.-", 1).parse::<State>(), too_many);
+    assert_eq!(text.replacen("0:.....", "0:..x..", 1).parse::<State>(), too_many);
+    Ok(())
+}
+
+#[test]
+fn from_parts() -> Result<(), AzulError> {
+    for position in random_game(3, 3)? {
+        let factories: Vec<&[Tile]> = position.factories().collect();
+        let boards: Vec<_> = (0..3).map(|player| position.board(player)).collect();
+        let state = State::from_parts(position.player(), position.bag(), position.box_top(), &factories, position.market(), &boards)?;
+        assert_eq!(state, position);
+        assert_eq!(state.zobrist(), position.zobrist());
+    }
+
+    // Checked like a position read from text
+    let state = State::new(2)?;
+    let boards: Vec<_> = (0..2).map(|player| state.board(player)).collect();
+    let empty: &[Tile] = &[];
+    assert_eq!(State::from_parts(0, state.bag(), state.box_top(), &[empty; 4], state.market(), &boards),
+        Err(AzulError::InvalidPosition("wrong number of factories")));
+    assert_eq!(State::from_parts(0, state.bag(), state.box_top(), &[&[Tile::Red; 5], empty, empty, empty, empty], state.market(), &boards),
+        Err(AzulError::InvalidPosition("factory")));
+    assert_eq!(State::from_parts(0, state.bag(), state.box_top(), &[empty; 5], empty, &boards),
+        Err(AzulError::InvalidPosition("there has to be one start tile")));
     Ok(())
 }
 
@@ -1,11 +1,18 @@
 #![feature(bench_black_box)]
 
+use std::convert::TryFrom;
 use rand::prelude::*;
 use rand::distributions::WeightedIndex;
 
 
 use modular_bitfield::prelude::*;
 
+use super::azul::{AzulError, GameMove, State, Tile, FLOOR_PENALTIES};
+
+// Only two players, so at most 5 factories with 4 colors and 5 colors in the market, each to 6 places,
+// 150 moves. tinyvec has no array of that size, 256 is the next one up
+pub type LegalMoves2 = tinyvec::ArrayVec<[GameMove; 256]>;
+
 #[derive(BitfieldSpecifier)]
 #[bits = 3]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -19,6 +26,8 @@ enum Tile2 {
     None
 }
 
+const COLORS2: [Tile2; 5] = [Tile2::Blue, Tile2::Yellow, Tile2::Red, Tile2::Black, Tile2::Teal];
+
 impl Tile2 {
     fn is_none(&self) -> bool {
         *self == Tile2::None
@@ -26,18 +35,70 @@ impl Tile2 {
     fn is_some(&self) -> bool {
         !self.is_none()
     }
+    fn tile(&self) -> Option<Tile> {
+        match self {
+            Tile2::Blue => Some(Tile::Blue),
+            Tile2::Yellow => Some(Tile::Yellow),
+            Tile2::Red => Some(Tile::Red),
+            Tile2::Black => Some(Tile::Black),
+            Tile2::Teal => Some(Tile::Teal),
+            Tile2::Start => Some(Tile::Start),
+            Tile2::None => None
+        }
+    }
+    fn from_tile(tile: Tile) -> Tile2 {
+        match tile {
+            Tile::Blue => Tile2::Blue,
+            Tile::Yellow => Tile2::Yellow,
+            Tile::Red => Tile2::Red,
+            Tile::Black => Tile2::Black,
+            Tile::Teal => Tile2::Teal,
+            Tile::Start => Tile2::Start
+        }
+    }
+    // Sorts like `Tile`, with no tile before all others like in a shorter factory
+    fn order(&self) -> u8 {
+        match self.tile() {
+            Some(tile) => tile as u8 + 1,
+            None => 0
+        }
+    }
+    fn letter(&self) -> char {
+        self.tile().map_or('-', |tile| tile.letter())
+    }
+    // Column of the color on a row of the wall
+    fn wall_index(&self, row: usize) -> usize {
+        (self.order() as usize - 2 + row) % 5
+    }
 }
 
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Player2 {
     One,
     Two
 }
+impl Player2 {
+    fn index(&self) -> usize {
+        match self {
+            Player2::One => 0,
+            Player2::Two => 1
+        }
+    }
+    fn from_index(index: usize) -> Player2 {
+        match index {
+            0 => Player2::One,
+            _ => Player2::Two
+        }
+    }
+    fn next(&self) -> Player2 {
+        Player2::from_index((self.index() + 1) % 2)
+    }
+}
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Bag2 {
     blue: B5,
     yellow: B5,
@@ -45,10 +106,38 @@ struct Bag2 {
     black: B5,
     teal: B5,
 }
+impl Bag2 {
+    fn get(&self, color: Tile2) -> u8 {
+        match color {
+            Tile2::Blue => self.blue(),
+            Tile2::Yellow => self.yellow(),
+            Tile2::Red => self.red(),
+            Tile2::Black => self.black(),
+            Tile2::Teal => self.teal(),
+            _ => 0
+        }
+    }
+    fn set(&mut self, color: Tile2, n: u8) {
+        match color {
+            Tile2::Blue => self.set_blue(n),
+            Tile2::Yellow => self.set_yellow(n),
+            Tile2::Red => self.set_red(n),
+            Tile2::Black => self.set_black(n),
+            Tile2::Teal => self.set_teal(n),
+            _ => {}
+        }
+    }
+    fn counts(&self) -> [u8; 5] {
+        [self.blue(), self.yellow(), self.red(), self.black(), self.teal()]
+    }
+    fn is_empty(&self) -> bool {
+        self.counts().iter().all(|n| *n == 0)
+    }
+}
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Factory2 {
     one: Tile2,
     two: Tile2,
@@ -87,11 +176,43 @@ impl Factory2 {
         }
         Err(tile)
     }
+    fn empty() -> Factory2 {
+        Factory2::from_tiles([Tile2::None; 4])
+    }
+    fn from_tiles(tiles: [Tile2; 4]) -> Factory2 {
+        Factory2::new()
+            .with_one(tiles[0])
+            .with_two(tiles[1])
+            .with_three(tiles[2])
+            .with_four(tiles[3])
+    }
+    fn tiles(&self) -> [Tile2; 4] {
+        [self.one(), self.two(), self.three(), self.four()]
+    }
+    fn count(&self, color: Tile2) -> u8 {
+        self.tiles().iter().filter(|tile| **tile == color).count() as u8
+    }
+    fn len(&self) -> u8 {
+        self.tiles().iter().filter(|tile| tile.is_some()).count() as u8
+    }
+    fn key(&self) -> [u8; 4] {
+        let tiles = self.tiles();
+        [tiles[0].order(), tiles[1].order(), tiles[2].order(), tiles[3].order()]
+    }
+    fn sort(&mut self) {
+        let mut tiles = self.tiles();
+        tiles.sort_unstable_by_key(|tile| match tile.order() {
+            // Missing tiles stay at the end
+            0 => u8::MAX,
+            order => order
+        });
+        *self = Factory2::from_tiles(tiles);
+    }
 }
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Factories2 {
     one: Factory2,
     two: Factory2,
@@ -107,34 +228,50 @@ impl Factories2 {
             && self.four().is_empty()
             && self.five().is_empty()
     }
+    /// Puts the tile in the first factory with room for it.
+    /// The getters return copies, so the factory has to be written back
     fn add_to_firsts(&mut self, tile: Tile2) -> Result<(), Tile2>{
-        if !self.one().is_full() {
-            self.one().add_to_first(tile);
-            return Ok(())
-        }
-        else if !self.two().is_full() {
-            self.two().add_to_first(tile);
-            return Ok(())
+        for i in 0..5 {
+            let mut factory = self.get(i);
+            if !factory.is_full() {
+                factory.add_to_first(tile)?;
+                self.set(i, factory);
+                return Ok(())
+            }
         }
-        else if !self.three().is_full() {
-            self.three().add_to_first(tile);
-            return Ok(())
+        Err(tile)
+    }
+    fn get(&self, i: usize) -> Factory2 {
+        match i {
+            0 => self.one(),
+            1 => self.two(),
+            2 => self.three(),
+            3 => self.four(),
+            _ => self.five()
         }
-        else if !self.four().is_full() {
-            self.four().add_to_first(tile);
-            return Ok(())
+    }
+    fn set(&mut self, i: usize, factory: Factory2) {
+        match i {
+            0 => self.set_one(factory),
+            1 => self.set_two(factory),
+            2 => self.set_three(factory),
+            3 => self.set_four(factory),
+            _ => self.set_five(factory)
         }
-        else if !self.five().is_full() {
-            self.five().add_to_first(tile);
-            return Ok(())
+    }
+    /// In the same order as the factories of `State`, which are sorted after every change
+    fn sort(&mut self) {
+        let mut factories = [self.one(), self.two(), self.three(), self.four(), self.five()];
+        factories.sort_unstable_by_key(|factory| factory.key());
+        for (i, factory) in factories.iter().enumerate() {
+            self.set(i, *factory);
         }
-        Err(tile)
     }
 }
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Row2 {
     one: bool,
     two: bool,
@@ -145,7 +282,7 @@ struct Row2 {
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct  Wall2 {
     one: Row2,
     two: Row2,
@@ -153,9 +290,47 @@ struct  Wall2 {
     four: Row2,
     five: Row2
 }
+impl Wall2 {
+    fn cells(&self) -> [[bool; 5]; 5] {
+        let row = |row: Row2| [row.one(), row.two(), row.three(), row.four(), row.five()];
+        [row(self.one()), row(self.two()), row(self.three()), row(self.four()), row(self.five())]
+    }
+    fn from_cells(cells: [[bool; 5]; 5]) -> Wall2 {
+        let row = |row: [bool; 5]| Row2::new()
+            .with_one(row[0])
+            .with_two(row[1])
+            .with_three(row[2])
+            .with_four(row[3])
+            .with_five(row[4]);
+        Wall2::new()
+            .with_one(row(cells[0]))
+            .with_two(row(cells[1]))
+            .with_three(row(cells[2]))
+            .with_four(row(cells[3]))
+            .with_five(row(cells[4]))
+    }
+}
+
+// Points for a tile placed on the wall, the same as `Board::connected` of the main engine
+fn points(wall: &[[bool; 5]; 5], row: usize, column: usize) -> i16 {
+    let run = |cells: [bool; 5], at: usize| {
+        let start = (0..at).rev().take_while(|i| cells[*i]).count();
+        let end = (at + 1..5).take_while(|i| cells[*i]).count();
+        start + 1 + end
+    };
+    let horizontal = run(wall[row], column);
+    let vertical = run([wall[0][column], wall[1][column], wall[2][column], wall[3][column], wall[4][column]], row);
+    match (horizontal, vertical) {
+        (1, 1) => 1,
+        (horizontal, 1) => horizontal as i16,
+        (1, vertical) => vertical as i16,
+        (horizontal, vertical) => (horizontal + vertical) as i16
+    }
+}
+
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Market2 {
     blue: B5,
     yellow: B5,
@@ -164,6 +339,44 @@ struct Market2 {
     teal: B5,
     start: bool
 }
+impl Market2 {
+    fn get(&self, color: Tile2) -> u8 {
+        match color {
+            Tile2::Blue => self.blue(),
+            Tile2::Yellow => self.yellow(),
+            Tile2::Red => self.red(),
+            Tile2::Black => self.black(),
+            Tile2::Teal => self.teal(),
+            Tile2::Start => self.start() as u8,
+            Tile2::None => 0
+        }
+    }
+    fn set(&mut self, color: Tile2, n: u8) {
+        match color {
+            Tile2::Blue => self.set_blue(n),
+            Tile2::Yellow => self.set_yellow(n),
+            Tile2::Red => self.set_red(n),
+            Tile2::Black => self.set_black(n),
+            Tile2::Teal => self.set_teal(n),
+            Tile2::Start => self.set_start(n != 0),
+            Tile2::None => {}
+        }
+    }
+    /// Regular tiles, without the start tile
+    fn colors(&self) -> u8 {
+        COLORS2.iter().map(|color| self.get(*color)).sum()
+    }
+    /// All tiles, in the order `State` keeps them
+    fn tiles(&self) -> Vec<Tile2> {
+        let mut tiles = Vec::new();
+        for tile in [Tile2::Start].iter().chain(COLORS2.iter()) {
+            for _ in 0..self.get(*tile) {
+                tiles.push(*tile);
+            }
+        }
+        tiles
+    }
+}
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
@@ -188,7 +401,7 @@ struct Pattern2_5 (Tile2, B3);
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Patterns2 {
     one: Pattern2_1,
     two: Pattern2_2,
@@ -196,16 +409,73 @@ struct Patterns2 {
     four: Pattern2_4,
     five: Pattern2_5,
 }
+impl Patterns2 {
+    /// The color and number of tiles on a pattern line, counting rows from 0
+    fn get(&self, row: usize) -> (Tile2, u8) {
+        match row {
+            0 => (self.one().get_0(), self.one().get_1()),
+            1 => (self.two().get_0(), self.two().get_1()),
+            2 => (self.three().get_0(), self.three().get_1()),
+            3 => (self.four().get_0(), self.four().get_1()),
+            _ => (self.five().get_0(), self.five().get_1())
+        }
+    }
+    fn set(&mut self, row: usize, color: Tile2, n: u8) {
+        match row {
+            0 => self.set_one(Pattern2_1::new().with_0(color).with_1(n)),
+            1 => self.set_two(Pattern2_2::new().with_0(color).with_1(n)),
+            2 => self.set_three(Pattern2_3::new().with_0(color).with_1(n)),
+            3 => self.set_four(Pattern2_4::new().with_0(color).with_1(n)),
+            _ => self.set_five(Pattern2_5::new().with_0(color).with_1(n))
+        }
+    }
+}
 
 #[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Board2 {
-    score: B7,
+    // At most 10 points for each of the 25 tiles on the wall
+    score: B8,
     wall: Wall2,
     patterns: Patterns2,
     floor: Market2
 }
+impl Board2 {
+    /// Whether the floor (0) or a pattern line (1-5) can take tiles of a color
+    fn check_pattern(&self, color: Tile, pattern: usize) -> Result<(), AzulError> {
+        match pattern {
+            0 => Ok(()),
+            1..=5 => {
+                let row = pattern - 1;
+                let column = Tile2::from_tile(color).wall_index(row);
+                if self.wall().cells()[row][column] {
+                    return Err(AzulError::WallTaken { row, column, color })
+                }
+                let (existing, n) = self.patterns().get(row);
+                match existing.tile() {
+                    Some(existing) if n > 0 && existing != color => {
+                        return Err(AzulError::PatternHasOtherColor { pattern, color, existing })
+                    },
+                    _ => {}
+                }
+                if n as usize == pattern {
+                    return Err(AzulError::PatternFull(pattern))
+                }
+                Ok(())
+            },
+            _ => Err(AzulError::InvalidPattern(pattern))
+        }
+    }
+    fn has_full_row(&self) -> bool {
+        self.wall().cells().iter().any(|row| row.iter().all(|x| *x))
+    }
+}
+
+// Tiles on a floor, which `add_to_floor` keeps at 7 at most
+fn floor_len(floor: &Market2) -> usize {
+    floor.colors() as usize + floor.start() as usize
+}
 
 /*#[bitfield(filled = false)]
 #[derive(BitfieldSpecifier)]
@@ -225,7 +495,7 @@ pub struct Game2 {
     board_1: Board2,
     board_2: Board2,
     #[skip]
-    unused: B7
+    unused: B5
 }
 
 impl Game2 {
@@ -428,63 +698,397 @@ impl Game2 {
             );
         game
     }
-    pub fn fill(&mut self, mut rng: StdRng) -> Result<(), &'static str> {    
+    /// Draws tiles for the factories like `State::fill`, the same `rng` draws the same tiles
+    pub fn fill(&mut self, mut rng: StdRng) -> Result<(), AzulError> {
         if !self.factories().is_empty() {
-            return Err("Factories are not empty");
+            return Err(AzulError::FactoriesNotEmpty);
         }
 
+        let mut bag = self.bag();
+        let mut factories = self.factories();
         for _ in 0..(4*5) {
-            let choices = [Tile2::Blue, Tile2::Yellow, Tile2::Red, Tile2::Black, Tile2::Teal];
-            let weights = [self.bag().blue(), self.bag().yellow(), self.bag().red(), self.bag().black(), self.bag().teal()];
-            let dist = WeightedIndex::new(&weights).unwrap();
+            if bag.is_empty() && !self.box_top().is_empty() {
+                bag = self.box_top();
+                self.set_box_top(Bag2::new());
+            }
+            else if bag.is_empty() {
+                break;
+            }
 
+            let choices = COLORS2;
+            let weights = bag.counts();
+            let dist = WeightedIndex::new(&weights).unwrap();
             let tile = choices[dist.sample(&mut rng)];
 
-/*            match self.set_factories(self.factories().add_to_firsts(tile)) {
-                Err(e) => return Err("Couldn't add tile to factory"),
-                Ok(_) => {
-                    if tile == Tile2::Blue {
-                        self.set_bag(self.bag().with_blue(self.bag().blue() - 1));
-                    }
-                    else if tile == Tile2::Yellow {
-                        self.set_bag(self.bag().with_yellow(self.bag().yellow() - 1));
+            // Every factory is full, the tile stays in the bag
+            if factories.add_to_firsts(tile).is_err() {
+                break;
+            }
+            bag.set(tile, bag.get(tile) - 1);
+        }
+        // Only factories that got all their tiles are sorted, like in `State::fill`
+        for i in 0..5 {
+            let mut factory = factories.get(i);
+            if factory.is_full() {
+                factory.sort();
+                factories.set(i, factory);
+            }
+        }
+        factories.sort();
+
+        self.set_bag(bag);
+        self.set_factories(factories);
+        Ok(())
+    }
+    fn board(&self, player: usize) -> Board2 {
+        match player {
+            0 => self.board_1(),
+            _ => self.board_2()
+        }
+    }
+    fn set_board(&mut self, player: usize, board: Board2) {
+        match player {
+            0 => self.set_board_1(board),
+            _ => self.set_board_2(board)
+        }
+    }
+    /// Checks a move against the rules, with the same errors as `State::validate`
+    pub fn validate(&self, game_move: GameMove) -> Result<(), AzulError> {
+        let board = self.board(self.player().index());
+        let GameMove(source, color, pattern) = game_move;
+
+        if color == Tile::Start {
+            return Err(AzulError::TakeStartTile)
+        }
+
+        if source == 0 {
+            let market = self.market();
+            if market.colors() == 0 && !market.start() {
+                return Err(AzulError::MarketEmpty)
+            }
+            else if market.get(Tile2::from_tile(color)) == 0 {
+                return Err(AzulError::NotInMarket(color))
+            }
+        }
+        else if source > 5 {
+            return Err(AzulError::FactoryOutOfBounds(source))
+        }
+        else if self.factories().get(source - 1).count(Tile2::from_tile(color)) == 0 {
+            return Err(AzulError::NotInFactory { factory: source, color })
+        }
+
+        board.check_pattern(color, pattern)
+    }
+    /// Every legal move for the current player, in the same order as `State::legal_moves`
+    pub fn legal_moves(&self) -> LegalMoves2 {
+        let board = self.board(self.player().index());
+        let market = self.market();
+        let factories = self.factories();
+        let mut moves = LegalMoves2::new();
+
+        for color in COLORS2.iter() {
+            let tile = color.tile().unwrap_or_default();
+            let mut patterns = [false; 6];
+            for pattern in 0..6 {
+                patterns[pattern] = board.check_pattern(tile, pattern).is_ok();
+            }
+
+            for source in 0..=5 {
+                let n = match source {
+                    0 => market.get(*color),
+                    _ => factories.get(source - 1).count(*color)
+                };
+                if n == 0 {
+                    continue;
+                }
+                for pattern in 0..6 {
+                    if patterns[pattern] {
+                        moves.push(GameMove(source, tile, pattern));
                     }
                 }
-            }; */
+            }
+        }
+        moves
+    }
+    /// Checks and does a move. If it's not legal the game is left as it was
+    pub fn do_move(&mut self, game_move: GameMove) -> Result<(), AzulError> {
+        self.validate(game_move)?;
+        let GameMove(source, color, pattern) = game_move;
+        let color = Tile2::from_tile(color);
+        let player = self.player().index();
+        let mut board = self.board(player);
+        let mut market = self.market();
+        let mut box_top = self.box_top();
+
+        let (start, taken) = match source {
+            0 => {
+                let taken = (market.start(), market.get(color));
+                market.set(color, 0);
+                market.set_start(false);
+                taken
+            },
+            _ => {
+                let mut factories = self.factories();
+                let factory = factories.get(source - 1);
+                for tile in factory.tiles().iter().filter(|tile| tile.is_some() && **tile != color) {
+                    market.set(*tile, market.get(*tile) + 1);
+                }
+                factories.set(source - 1, Factory2::empty());
+                factories.sort();
+                self.set_factories(factories);
+                (false, factory.count(color))
+            }
+        };
 
-            println!("{:?}", tile);
-            println!("{:#?}", self.bag());
-            //println!("{:#?}", self.factories());
+        // The start tile comes first, and always gets a place on the floor
+        let mut floor = board.floor();
+        if start {
+            if floor_len(&floor) == FLOOR_PENALTIES.len() {
+                if let Some(bumped) = COLORS2.iter().rev().find(|tile| floor.get(**tile) > 0) {
+                    floor.set(*bumped, floor.get(*bumped) - 1);
+                    box_top.set(*bumped, box_top.get(*bumped) + 1);
+                }
+            }
+            floor.set_start(true);
+        }
+        let mut patterns = board.patterns();
+        for _ in 0..taken {
+            let (_, n) = patterns.get(pattern.saturating_sub(1));
+            if pattern != 0 && (n as usize) < pattern {
+                patterns.set(pattern - 1, color, n + 1);
+            }
+            else if floor_len(&floor) < FLOOR_PENALTIES.len() {
+                floor.set(color, floor.get(color) + 1);
+            }
+            else {
+                box_top.set(color, box_top.get(color) + 1);
+            }
         }
 
+        board.set_floor(floor);
+        board.set_patterns(patterns);
+        self.set_board(player, board);
+        self.set_market(market);
+        self.set_box_top(box_top);
+
+        if self.is_round_over() {
+            self.score();
+            self.cleanup();
+        }
+        else {
+            self.set_player(self.player().next());
+        }
         Ok(())
-/*        for factory in &mut self.factories {
-            for _ in 0..4 {
-                if self.bag.len() == 0 && self.box_top.len() > 0 {
-                    self.bag.append(&mut self.box_top);
-                }
-                else if self.bag.len() == 0 {
-                    return Ok(())
+    }
+    /// Like `State::score`
+    fn score(&mut self) {
+        let mut box_top = self.box_top();
+        for player in 0..2 {
+            let mut board = self.board(player);
+            let mut wall = board.wall().cells();
+            let mut patterns = board.patterns();
+            let mut score = board.score() as i16;
+            for row in 0..5 {
+                let (color, n) = patterns.get(row);
+                if n as usize == row + 1 {
+                    let column = color.wall_index(row);
+                    wall[row][column] = true;
+                    score += points(&wall, row, column);
+                    box_top.set(color, box_top.get(color) + row as u8);
+                    patterns.set(row, Tile2::None, 0);
                 }
-                else {
-                    let tile_i:usize = rng.gen_range(0..self.bag.len());
-                    let tile = self.bag.remove(tile_i);
-                    factory.push(tile);
+            }
+            let negative: i16 = FLOOR_PENALTIES[..floor_len(&board.floor())].iter().sum();
+            board.set_score(std::cmp::max(score - negative, 0) as u8);
+            board.set_wall(Wall2::from_cells(wall));
+            board.set_patterns(patterns);
+            self.set_board(player, board);
+        }
+        self.set_box_top(box_top);
+    }
+    /// Like `State::cleanup`
+    fn cleanup(&mut self) {
+        let mut box_top = self.box_top();
+        let mut starting = None;
+        for player in 0..2 {
+            let mut board = self.board(player);
+            let floor = board.floor();
+            for color in COLORS2.iter() {
+                box_top.set(*color, box_top.get(*color) + floor.get(*color));
+            }
+            if floor.start() {
+                starting = Some(player);
+            }
+            board.set_floor(Market2::new());
+            self.set_board(player, board);
+        }
+        self.set_box_top(box_top);
+
+        match starting {
+            Some(player) => {
+                self.set_market(self.market().with_start(true));
+                self.set_player(Player2::from_index(player));
+            },
+            None => self.set_player(self.player().next())
+        }
+    }
+    /// Like `State::is_round_over`
+    pub fn is_round_over(&self) -> bool {
+        self.factories().is_empty() && self.market().colors() == 0
+    }
+    /// Like `State::tiles_left`
+    pub fn tiles_left(&self) -> u8 {
+        (0..5).map(|i| self.factories().get(i).len()).sum::<u8>() + self.market().colors()
+    }
+    /// Like `State::is_over`
+    pub fn is_over(&self) -> bool {
+        self.is_round_over() && (0..2).any(|player| self.board(player).has_full_row())
+    }
+    /// Only two player games fit. The factories are taken in the same order as in `state`
+    pub fn from_state(state: &State) -> Result<Game2, AzulError> {
+        if state.players() != 2 {
+            return Err(AzulError::InvalidPlayerCount(state.players()));
+        }
+        let mut game = Game2::create();
+        game.set_player(Player2::from_index(state.player() as usize));
+        game.set_bag(bag2(state.bag()));
+        game.set_box_top(bag2(state.box_top()));
+
+        let mut factories = game.factories();
+        for (i, tiles) in state.factories().enumerate() {
+            let mut factory = [Tile2::None; 4];
+            for (slot, tile) in factory.iter_mut().zip(tiles) {
+                *slot = Tile2::from_tile(*tile);
+            }
+            factories.set(i, Factory2::from_tiles(factory));
+        }
+        game.set_factories(factories);
+        game.set_market(market2(state.market()));
+
+        for player in 0..2 {
+            let (score, wall, floor, patterns) = state.board(player);
+            let mut board = game.board(player);
+            board.set_score(u8::try_from(score).map_err(|_| AzulError::InvalidPosition("score"))?);
+            board.set_wall(Wall2::from_cells(wall));
+            board.set_floor(market2(floor));
+
+            let mut lines = board.patterns();
+            for (row, tiles) in patterns.iter().enumerate() {
+                match tiles.first() {
+                    Some(color) => lines.set(row, Tile2::from_tile(*color), tiles.len() as u8),
+                    None => lines.set(row, Tile2::None, 0)
                 }
             }
-        };
-*/
+            board.set_patterns(lines);
+            game.set_board(player, board);
+        }
+        Ok(game)
+    }
+    /// The same position as a `State`, put together from the parts `from_state` reads
+    pub fn to_state(&self) -> Result<State, AzulError> {
+        let tiles = |tiles: &[Tile2]| tiles.iter().filter_map(|tile| tile.tile()).collect::<Vec<Tile>>();
+
+        let factories: Vec<Vec<Tile>> = (0..5).map(|i| tiles(&self.factories().get(i).tiles())).collect();
+        let factories: Vec<&[Tile]> = factories.iter().map(|factory| factory.as_slice()).collect();
+        let market = tiles(&self.market().tiles());
+
+        let boards: Vec<(i16, [[bool; 5]; 5], Vec<Tile>, Vec<Vec<Tile>>)> = (0..2).map(|player| {
+            let board = self.board(player);
+            let patterns = (0..5).map(|row| {
+                let (color, n) = board.patterns().get(row);
+                tiles(&vec![color; n as usize])
+            }).collect();
+            (board.score() as i16, board.wall().cells(), tiles(&board.floor().tiles()), patterns)
+        }).collect();
+        let boards: Vec<_> = boards.iter().map(|(score, wall, floor, patterns)| {
+            (*score, *wall, floor.as_slice(), [&patterns[0][..], &patterns[1][..], &patterns[2][..], &patterns[3][..], &patterns[4][..]])
+        }).collect();
+
+        State::from_parts(self.player().index() as u8, self.bag().counts(), self.box_top().counts(), &factories, &market, &boards)
     }
-    /*pub fn do_move(&mut self, game_move: GameMove) -> Result<(), &'static str> {
-        match game_move {
-            () => 
+}
+
+fn write_tiles2(f: &mut std::fmt::Formatter, tiles: &[Tile2]) -> std::fmt::Result {
+    let tiles: String = tiles.iter().filter(|tile| tile.is_some()).map(|tile| tile.letter()).collect();
+    match tiles.is_empty() {
+        true => write!(f, "-"),
+        false => write!(f, "{}", tiles)
+    }
+}
+
+/// The same text as `State` uses for positions
+impl std::fmt::Display for Game2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [blue, yellow, red, black, teal] = self.bag().counts();
+        write!(f, "{} {},{},{},{},{} ", self.player().index(), blue, yellow, red, black, teal)?;
+        let [blue, yellow, red, black, teal] = self.box_top().counts();
+        write!(f, "{},{},{},{},{} ", blue, yellow, red, black, teal)?;
+
+        for i in 0..5 {
+            if i != 0 {
+                write!(f, ".")?;
+            }
+            write_tiles2(f, &self.factories().get(i).tiles())?;
+        }
+        write!(f, " ")?;
+        write_tiles2(f, &self.market().tiles())?;
+
+        for player in 0..2 {
+            let board = self.board(player);
+            write!(f, " {}:", board.score())?;
+            for (i, row) in board.wall().cells().iter().enumerate() {
+                if i != 0 {
+                    write!(f, "/")?;
+                }
+                for taken in row {
+                    write!(f, "{}", if *taken { 'x' } else { '.' })?;
+                }
+            }
+            write!(f, ":")?;
+            write_tiles2(f, &board.floor().tiles())?;
+            write!(f, ":")?;
+            for row in 0..5 {
+                if row != 0 {
+                    write!(f, "/")?;
+                }
+                let (color, n) = board.patterns().get(row);
+                write_tiles2(f, &vec![color; n as usize])?;
+            }
         }
-    }*/
+        Ok(())
+    }
+}
+
+// `State` never has more than 20 tiles of a color, so they fit in the bitfields
+fn bag2(counts: [u8; 5]) -> Bag2 {
+    let mut bag = Bag2::new();
+    for (color, n) in COLORS2.iter().zip(counts) {
+        bag.set(*color, n);
+    }
+    bag
+}
+
+fn market2(tiles: &[Tile]) -> Market2 {
+    let mut market = Market2::new();
+    for tile in tiles.iter().map(|tile| Tile2::from_tile(*tile)) {
+        market.set(tile, market.get(tile) + 1);
+    }
+    market
+}
+
+/// Only two player games fit. The position is read and checked by `State` first,
+/// so it's in the same order as there
+impl std::str::FromStr for Game2 {
+    type Err = AzulError;
+
+    fn from_str(text: &str) -> Result<Game2, AzulError> {
+        Game2::from_state(&text.parse()?)
+    }
 }
 
 use std::time::{Instant, Duration};
 
-pub fn size_of_bitfields() -> Result<(), &'static str> {
+pub fn size_of_bitfields() -> Result<(), AzulError> {
     println!("size of bitfield game: {}", std::mem::size_of::<Game2>());
     println!("size of state: {}", std::mem::size_of::<super::azul::State>());
 
@@ -516,3 +1120,65 @@ pub fn size_of_bitfields() -> Result<(), &'static str> {
 
 
 }
+
+#[test]
+fn add_to_firsts() {
+    let mut factories = Game2::create().factories();
+    for _ in 0..(4*5) {
+        assert!(factories.add_to_firsts(Tile2::Red).is_ok());
+    }
+    assert_eq!(factories.add_to_firsts(Tile2::Red), Err(Tile2::Red));
+    assert!((0..5).all(|i| factories.get(i).count(Tile2::Red) == 4));
+}
+
+#[test]
+fn same_as_state() -> Result<(), AzulError> {
+    let mut rng = StdRng::seed_from_u64(25);
+    let games = (0..10).map(|_| State::new(2)).chain([Ok(super::azul::complicated()?.state)]);
+    for state in games {
+        // Read back, so the factories are in order
+        let mut state: State = state?.to_string().parse()?;
+        let mut game = Game2::from_state(&state)?;
+        loop {
+            if state.is_round_over() && !state.is_over() {
+                let seed = rng.gen();
+                state.fill(StdRng::seed_from_u64(seed))?;
+                game.fill(StdRng::seed_from_u64(seed))?;
+            }
+            assert_eq!(game.to_string(), state.to_string());
+            assert_eq!((game.is_round_over(), game.is_over(), game.tiles_left()), (state.is_round_over(), state.is_over(), state.tiles_left()));
+
+            let moves = state.legal_moves();
+            let legal_moves: Vec<GameMove> = moves.iter().map(|x| x.game_move()).collect();
+            assert_eq!(game.legal_moves().as_slice(), legal_moves.as_slice());
+            // Up to 9 factories, so some of them are out of bounds
+            for game_move in GameMove::default().into_iter().chain([GameMove(0, Tile::Red, 6), GameMove(1, Tile::Start, 1)]) {
+                assert_eq!(game.validate(game_move), state.validate(game_move).map(|_| ()), "{:?}", game_move);
+            }
+
+            if moves.is_empty() {
+                break;
+            }
+            let valid_move = moves[rng.gen_range(0..moves.len())];
+            state.do_move(valid_move);
+            game.do_move(valid_move.game_move())?;
+        }
+        // Through every round to the end of the game
+        assert!(game.is_over());
+        assert_eq!(game.to_state()?, state);
+    }
+    Ok(())
+}
+
+#[test]
+fn position_text() -> Result<(), AzulError> {
+    let game = Game2::create();
+    assert_eq!(game.to_string(), State::new(2)?.to_string());
+    assert_eq!(game.to_string().parse::<Game2>()?.to_string(), game.to_string());
+    assert_eq!(State::new(3)?.to_string().parse::<Game2>().err(), Some(AzulError::InvalidPlayerCount(3)));
+    let mut game = game;
+    assert_eq!(game.do_move(GameMove(1, Tile::Blue, 1)), Err(AzulError::NotInFactory { factory: 1, color: Tile::Blue }));
+    game.fill(StdRng::seed_from_u64(42))?;
+    assert_eq!(game.fill(StdRng::seed_from_u64(42)), Err(AzulError::FactoriesNotEmpty));
+    Ok(())
+}
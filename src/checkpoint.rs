@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::tt::TranspositionTable;
+
+const HEADER: &str = "mercury checkpoint 1";
+
+// Key, depth and value of a table entry
+const TABLE_ENTRY: usize = 8 + 1 + 16;
+
+// Where a file is written before it replaces the old one
+pub fn temporary(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The root subtrees of an enumeration that are done, enough to pick it up again after a restart
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Zobrist hash of the position being counted
+    pub root: u64,
+    /// Finished subtrees by their place among the root moves,
+    /// with the hash of the position and how many lines follow it
+    pub done: BTreeMap<usize, (u64, u128)>,
+}
+impl Checkpoint {
+    pub fn new(root: u64) -> Checkpoint {
+        Checkpoint { root, done: BTreeMap::new() }
+    }
+    /// Reads a checkpoint written by `save`:
+    /// a header, the root, and then one line with the place, hash and count of every finished subtree
+    pub fn load(path: &Path) -> io::Result<Checkpoint> {
+        let file = io::BufReader::new(std::fs::File::open(path)?);
+        let mut lines = file.lines();
+
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid(format!("{} is not a checkpoint", path.display())));
+        }
+        let root = match lines.next().transpose()? {
+            Some(line) => line.strip_prefix("root ")
+                .and_then(|x| u64::from_str_radix(x, 16).ok())
+                .ok_or_else(|| invalid(format!("Bad root: {}", line)))?,
+            None => return Err(invalid("Checkpoint has no root".into()))
+        };
+
+        let mut checkpoint = Checkpoint::new(root);
+        for line in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                ["done", index, key, count] => index.parse().ok()
+                    .zip(u64::from_str_radix(key, 16).ok())
+                    .zip(count.parse().ok()),
+                _ => None
+            };
+            match parsed {
+                Some(((index, key), count)) => checkpoint.done.insert(index, (key, count)),
+                None => return Err(invalid(format!("Bad line: {}", line)))
+            };
+        }
+        Ok(checkpoint)
+    }
+    /// Writes to a temporary file first, so a crash while saving doesn't lose the last checkpoint
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = temporary(path);
+        let mut file = io::BufWriter::new(std::fs::File::create(&temporary)?);
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "root {:016x}", self.root)?;
+        for (index, (key, count)) in &self.done {
+            writeln!(file, "done {} {:016x} {}", index, key, count)?;
+        }
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(temporary, path)
+    }
+}
+
+pub fn save_table(table: &TranspositionTable<u128>, path: &Path) -> io::Result<()> {
+    let temporary = temporary(path);
+    let mut file = io::BufWriter::new(std::fs::File::create(&temporary)?);
+    let mut result = Ok(());
+    table.for_each(|key, depth, value| {
+        if result.is_ok() {
+            result = file.write_all(&key.to_le_bytes())
+                .and_then(|_| file.write_all(&[depth]))
+                .and_then(|_| file.write_all(&value.to_le_bytes()));
+        }
+    });
+    result?;
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(temporary, path)
+}
+
+pub fn load_table(table: &TranspositionTable<u128>, path: &Path) -> io::Result<()> {
+    let mut file = io::BufReader::new(std::fs::File::open(path)?);
+    let mut entry = [0u8; TABLE_ENTRY];
+    loop {
+        match file.read_exact(&mut entry) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e)
+        }
+        let mut key = [0; 8];
+        key.copy_from_slice(&entry[..8]);
+        let mut value = [0; 16];
+        value.copy_from_slice(&entry[9..]);
+        table.insert(u64::from_le_bytes(key), entry[8], u128::from_le_bytes(value));
+    }
+}
+
+/// Keeps a checkpoint on disk while an enumeration is running
+pub struct Checkpointer {
+    path: PathBuf,
+    /// Where to save the transposition table too, if at all
+    table_path: Option<PathBuf>,
+    interval: Duration,
+    saved: Mutex<Instant>,
+    checkpoint: Mutex<Checkpoint>,
+}
+impl Checkpointer {
+    /// Picks up the checkpoint at `path` if there is one, it has to be for the same root.
+    /// It's saved again every `interval`, and with `save_table` the table is saved next to it,
+    /// and loaded back into `table`
+    pub fn open(path: &Path, interval: Duration, save_table: bool, root: u64, table: &TranspositionTable<u128>) -> io::Result<Checkpointer> {
+        let checkpoint = match path.exists() {
+            true => Checkpoint::load(path)?,
+            false => Checkpoint::new(root)
+        };
+        if checkpoint.root != root {
+            return Err(invalid(format!("{} is for another position", path.display())));
+        }
+
+        let table_path = match save_table {
+            true => Some(path.with_extension("table")),
+            false => None
+        };
+        if let Some(table_path) = &table_path {
+            if table_path.exists() {
+                load_table(table, table_path)?;
+            }
+        }
+
+        Ok(Checkpointer {
+            path: path.to_path_buf(),
+            table_path,
+            interval,
+            saved: Mutex::new(Instant::now()),
+            checkpoint: Mutex::new(checkpoint),
+        })
+    }
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint.lock().expect("checkpoint lock").clone()
+    }
+    /// Records a finished subtree, and saves if it's been a while
+    pub fn finish(&self, index: usize, key: u64, count: u128, table: &TranspositionTable<u128>) -> io::Result<()> {
+        self.checkpoint.lock().expect("checkpoint lock").done.insert(index, (key, count));
+
+        let mut saved = self.saved.lock().expect("checkpoint lock");
+        if saved.elapsed() >= self.interval {
+            self.save(table)?;
+            *saved = Instant::now();
+        }
+        Ok(())
+    }
+    pub fn save(&self, table: &TranspositionTable<u128>) -> io::Result<()> {
+        self.checkpoint().save(&self.path)?;
+        if let Some(table_path) = &self.table_path {
+            save_table(table, table_path)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn save_load() -> io::Result<()> {
+    let directory = std::env::temp_dir().join(format!("mercury-checkpoint-{}", std::process::id()));
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.join("count.checkpoint");
+
+    let mut checkpoint = Checkpoint::new(0xdead_beef);
+    checkpoint.done.insert(3, (42, 1337));
+    checkpoint.done.insert(0, (u64::MAX, u128::MAX));
+    checkpoint.save(&path)?;
+    assert_eq!(Checkpoint::load(&path)?, checkpoint);
+
+    let table = TranspositionTable::new(1 << 16, super::tt::Replacement::Always);
+    table.insert(7, 3, 700);
+    table.insert(u64::MAX, 9, u128::MAX);
+    let table_path = directory.join("count.table");
+    save_table(&table, &table_path)?;
+    let loaded = TranspositionTable::new(1 << 16, super::tt::Replacement::Always);
+    load_table(&loaded, &table_path)?;
+    assert_eq!(loaded.get(7), Some(700));
+    assert_eq!(loaded.get(u64::MAX), Some(u128::MAX));
+
+    // Only for the same position
+    assert!(Checkpointer::open(&path, Duration::from_secs(60), false, 1, &loaded).is_err());
+    std::fs::write(&path, "something else")?;
+    assert!(Checkpoint::load(&path).is_err());
+
+    std::fs::remove_dir_all(&directory)
+}
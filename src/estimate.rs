@@ -0,0 +1,179 @@
+use super::azul::*;
+use super::progress::Progress;
+use rand::prelude::*;
+
+/// An estimate from random samples, with its standard error
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Estimate {
+    pub mean: f64,
+    pub std_error: f64,
+    /// What's known for sure from the samples, there are at least this many
+    pub known: f64,
+}
+impl Estimate {
+    /// The 95% confidence interval. The samples are very skewed, so the normal interval can
+    /// go far below zero, it's cut off at what's known instead
+    pub fn interval(&self) -> (f64, f64) {
+        ((self.mean - 1.96 * self.std_error).max(self.known), self.mean + 1.96 * self.std_error)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeSize {
+    /// Lines of play exactly this many moves long, counting from 0
+    pub depths: Vec<Estimate>,
+    /// Lines of play to the horizon, or shorter where the game ends first
+    pub lines: Estimate,
+    pub probes: u64,
+}
+
+// Sums of the samples and their squares, the samples that are missing were 0.
+// Squares of whole game samples don't fit in a float, so everything is divided by the biggest sample
+#[derive(Debug, Clone, Copy)]
+struct Sums {
+    scale: f64,
+    sum: f64,
+    squares: f64,
+}
+impl Default for Sums {
+    fn default() -> Self {
+        Sums { scale: 1.0, sum: 0.0, squares: 0.0 }
+    }
+}
+impl Sums {
+    fn add(&mut self, x: f64) {
+        if x > self.scale {
+            let factor = self.scale / x;
+            self.sum *= factor;
+            self.squares *= factor * factor;
+            self.scale = x;
+        }
+        self.sum += x / self.scale;
+        self.squares += (x / self.scale) * (x / self.scale);
+    }
+    fn estimate(&self, n: u64) -> Estimate {
+        // A sample that isn't 0 was a line that's really there
+        let known = if self.sum > 0.0 { 1.0 } else { 0.0 };
+        let n = n as f64;
+        let mean = self.sum / n;
+        if n < 2.0 {
+            return Estimate { mean: mean * self.scale, std_error: 0.0, known };
+        }
+        let variance = ((self.squares - n * mean * mean) / (n - 1.0)).max(0.0);
+        Estimate { mean: mean * self.scale, std_error: (variance / n).sqrt() * self.scale, known }
+    }
+}
+
+/// Knuth's estimator: walks down the tree picking random moves, and multiplies the number
+/// of moves at every step. The products are unbiased estimates of the size of the tree.
+///
+/// `depth` limits how many moves are played, `None` plays until the end. With `refills` the
+/// factories are filled again between rounds and the probes go on until the game is over,
+/// the fills are drawn at random like in a real game. Without it the probes stop when the
+/// round is over, like `count_options`.
+/// The products can get far too big for integers over whole games, so they're kept as floats
+pub fn estimate(state: &State, depth: Option<usize>, refills: bool, probes: u64, rng: &mut StdRng, progress: &Progress) -> TreeSize {
+    let mut depths: Vec<Sums> = Vec::new();
+    let mut lines = Sums::default();
+    progress.add_total(probes);
+    let mut nodes = progress.nodes();
+
+    for _ in 0..probes {
+        // Only the state, a probe doesn't need the history `Game` keeps
+        let mut state = *state;
+        let mut product = 1.0;
+        let mut moves = 0;
+
+        loop {
+            if depths.len() <= moves {
+                depths.push(Sums::default());
+            }
+            depths[moves].add(product);
+
+            if Some(moves) == depth || state.is_over() {
+                break;
+            }
+            let legal_moves = state.legal_moves();
+            if legal_moves.is_empty() {
+                break;
+            }
+            product *= legal_moves.len() as f64;
+
+            let valid_move = legal_moves[rng.gen_range(0..legal_moves.len())];
+            state.do_move(valid_move);
+            if refills && state.is_round_over() && !state.is_over() {
+                // Like `Game::do_move`, but every probe draws its own fills
+                state.fill(StdRng::seed_from_u64(rng.gen())).expect("the factories are empty at the end of a round");
+            }
+            moves += 1;
+            nodes.node(|| None);
+        }
+        lines.add(product);
+        nodes.flush();
+        progress.finish(1);
+        progress.tick(None);
+    }
+
+    let depths: Vec<Estimate> = depths.iter().map(|sums| sums.estimate(probes)).collect();
+    let mut lines = lines.estimate(probes);
+    // Every probe makes the same first move count, and every first move ends at least one line
+    if let Some(first) = depths.get(1) {
+        lines.known = lines.known.max(first.mean);
+    }
+    TreeSize {
+        depths,
+        lines,
+        probes,
+    }
+}
+
+#[test]
+fn estimate_round() -> Result<(), AzulError> {
+    let mut rng = StdRng::seed_from_u64(21);
+    let game = super::random_position(21, 9)?;
+
+    // Within the confidence interval, with some room so the test doesn't fail by chance
+    let close = |estimate: Estimate, exact: u128| {
+        (estimate.mean - exact as f64).abs() <= 4.0 * estimate.std_error + 1e-6
+    };
+
+    let progress = Progress::quiet();
+    let size = estimate(&game.state, None, false, 5_000, &mut rng, &progress);
+    assert_eq!(progress.status(None).done, 5_000);
+    let table = super::tt::TranspositionTable::new(1 << 20, super::tt::Replacement::Deeper);
+    let exact = super::count_options(game.state, 1, &table, &mut progress.nodes());
+    assert!(close(size.lines, exact), "{:?} {}", size.lines, exact);
+    let (low, high) = size.lines.interval();
+    assert!(low <= exact as f64 && exact as f64 <= high, "{} {} {}", low, exact, high);
+    assert_eq!(size.lines.known, game.legal_moves().len() as f64);
+
+    // Every depth on its own, the first move is always counted exactly
+    assert_eq!(size.depths[0], Estimate { mean: 1.0, std_error: 0.0, known: 1.0 });
+    assert_eq!(size.depths[1].mean, game.legal_moves().len() as f64);
+    let size = estimate(&game.state, Some(3), false, 5_000, &mut rng, &progress);
+    assert_eq!(size.depths.len(), 4);
+    assert!(close(size.lines, super::perft(&game.state, 3)));
+    Ok(())
+}
+
+#[test]
+fn estimate_game() -> Result<(), AzulError> {
+    let mut rng = StdRng::seed_from_u64(21);
+    let mut game = Game::new(2, 21)?;
+    game.fill()?;
+
+    let size = estimate(&game.state, None, true, 20, &mut rng, &Progress::quiet());
+    // At least five rounds of at least five moves each
+    assert!(size.depths.len() > 25);
+    assert!(size.lines.mean > 1e20);
+    assert!(size.depths.iter().all(|estimate| estimate.mean.is_finite() && estimate.std_error.is_finite()));
+    assert!(size.lines.std_error > 0.0);
+    // The samples are so skewed the normal interval goes below zero
+    assert!(size.lines.mean < 1.96 * size.lines.std_error);
+    assert!(size.lines.interval().0 >= 1.0);
+
+    // Without refills nothing happens after the first round
+    let size = estimate(&game.state, None, false, 20, &mut rng, &Progress::quiet());
+    assert!(size.depths.len() <= 21);
+    Ok(())
+}
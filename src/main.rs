@@ -1,10 +1,18 @@
 #![feature(test)]
-use cached::proc_macro::cached;
-
 mod azul;
 use azul::*;
 mod azul2;
 use azul2::*;
+mod tt;
+use tt::*;
+mod estimate;
+use estimate::*;
+mod checkpoint;
+use checkpoint::*;
+mod units;
+use units::*;
+mod progress;
+use progress::*;
 use rand::prelude::*;
 
 use thousands::Separable;
@@ -15,139 +23,625 @@ use thousands::Separable;
 //#[global_allocator]
 //static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-fn main() -> Result<(), &'static str> {
+// Seconds between progress reports
+const PROGRESS_INTERVAL: u64 = 5;
 
-    let program = std::env::args().nth(1).expect("no program given")
-        .parse().unwrap_or(1);
+/// Named arguments like `--threads=4`, and switches like `--csv`
+struct Flags(Vec<(String, Option<String>)>);
+impl Flags {
+    fn parse(flags: &[String]) -> Flags {
+        Flags(flags.iter().map(|flag| {
+            let flag = flag.trim_start_matches("--");
+            match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (flag.to_string(), None)
+            }
+        }).collect())
+    }
+    /// Fails on any flag not in `allowed`, so a typo doesn't quietly count something else
+    fn only(&self, allowed: &[&str]) -> Result<(), String> {
+        match self.0.iter().find(|(name, _)| !allowed.contains(&name.as_str())) {
+            Some((name, _)) => Err(format!("Unknown flag --{}", name)),
+            None => Ok(())
+        }
+    }
+    fn text(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.0.iter().rev().find(|(flag, _)| flag == name) {
+            Some((_, Some(value))) => Ok(Some(value)),
+            Some((_, None)) => Err(format!("--{} needs a value", name)),
+            None => Ok(None)
+        }
+    }
+    fn value<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.text(name)? {
+            Some(value) => value.parse().map_err(|_| format!("Bad value for --{}: {}", name, value)),
+            None => Ok(default)
+        }
+    }
+    fn switch(&self, name: &str) -> Result<bool, String> {
+        match self.0.iter().find(|(flag, _)| flag == name) {
+            Some((_, Some(_))) => Err(format!("--{} doesn't take a value", name)),
+            Some((_, None)) => Ok(true),
+            None => Ok(false)
+        }
+    }
+}
 
-    // size_of_stuff();
+// Every program takes these
+const PROGRESS_FLAGS: [&str; 2] = ["json", "quiet"];
+// Memory for the transposition table in megabytes, what it throws out when it's full,
+// and the number of threads
+const COUNT_FLAGS: [&str; 5] = ["json", "quiet", "table", "replace", "threads"];
 
-    return match program {
-        1 => {
-            let mut game = some_game()?;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    let (flags, args): (Vec<String>, Vec<String>) = std::env::args().partition(|x| x.starts_with("--"));
+    let flags = Flags::parse(&flags);
+    // Progress goes to stderr, as text or with --json as JSON lines, --quiet turns it off
+    let format = match (flags.switch("quiet")?, flags.switch("json")?) {
+        (true, _) => Format::Quiet,
+        (false, true) => Format::Json,
+        (false, false) => Format::Human
+    };
+    let interval = std::time::Duration::from_secs(PROGRESS_INTERVAL);
+    let table_size: usize = flags.value("table", 1024)?;
+    let replacement = flags.value("replace", Replacement::Deeper)?;
+    let threads: usize = flags.value("threads", 1)?;
 
-            game.do_move(GameMove(2, Tile::Red, 3))?;
-            game.do_move(GameMove(5, Tile::Yellow, 2))?;
+    let command = args.get(1).cloned().ok_or("No program given")?;
+    // Counting split over several processes, see `split`, `work` and `merge`.
+    // They take a directory or a unit file after the command
+    if ["split", "work", "merge"].contains(&command.as_str()) {
+        if args.len() != 3 {
+            return Err(format!("{} takes one path", command).into());
+        }
+    } else if args.len() > 2 {
+        return Err(format!("Unexpected argument {}, options are flags like --threads=4", args[2]).into());
+    }
+    match command.as_str() {
+        "split" => {
+            flags.only(&[&PROGRESS_FLAGS[..], &["plies", "position"]].concat())?;
+            let plies = flags.value("plies", 2)?;
+            // The position to split, in text, or the same as program 1
+            let state = match flags.text("position")? {
+                Some(path) => std::fs::read_to_string(path)?.trim().parse()?,
+                None => some_position()?.state
+            };
+            let units = split(state, 1, plies, std::path::Path::new(&args[2]))?;
+            println!("{} units", units);
+            return Ok(());
+        },
+        "work" => {
+            flags.only(&COUNT_FLAGS)?;
+            let table = TranspositionTable::new(table_size << 20, replacement);
+            let progress = Progress::new("work", format, interval);
+            println!("{}", work(std::path::Path::new(&args[2]), &table, threads, &progress)?);
+            progress.report(Some(table.stats()));
+            return Ok(());
+        },
+        "merge" => {
+            flags.only(&PROGRESS_FLAGS)?;
+            println!("{}", merge(std::path::Path::new(&args[2]))?);
+            return Ok(());
+        },
+        _ => {}
+    }
 
-            game.do_move(GameMove(3, Tile::Blue, 2))?;
-            //game.do_move(GameMove(0, Tile::Black, 4))?;
+    let program: u8 = command.parse().map_err(|_| format!("Not a valid program: {}", command))?;
+    // Counting can be picked up again from a checkpoint, optionally with the table
+    let count_flags = [&COUNT_FLAGS[..], &["checkpoint", "save-table"]].concat();
+    let checkpoint = flags.text("checkpoint")?;
+    let save_table = flags.switch("save-table")?;
+    if save_table && checkpoint.is_none() {
+        return Err("--save-table goes with --checkpoint".into());
+    }
 
-            //game.do_move(GameMove(5, Tile::Black, 1))?;
-            //game.do_move(GameMove(0, Tile::Blue, 3))?;
+    // size_of_stuff();
 
-            println!("{}", count_options(game, 1, 2));
+    match program {
+        1 => {
+            flags.only(&count_flags)?;
+            let game = some_position()?;
+            let table = TranspositionTable::new(table_size << 20, replacement);
+            let progress = Progress::new("count", format, interval);
+            println!("{}", count(game.state, 1, &table, threads, &progress, checkpoint, save_table)?);
+            progress.report(Some(table.stats()));
+            print_stats(&table);
             Ok(())
         },
-        2 => calculate_options(),
-        3 => size_of_bitfields(),
+        2 => {
+            flags.only(&count_flags)?;
+            calculate_options(table_size, replacement, threads, false, checkpoint, save_table, &Progress::new("count", format, interval))
+        },
+        3 => {
+            flags.only(&PROGRESS_FLAGS)?;
+            Ok(size_of_bitfields()?)
+        },
         4 => {
+            flags.only(&PROGRESS_FLAGS)?;
             let mut game = Game2::create();
-            game.fill(StdRng::seed_from_u64(42));
+            game.fill(StdRng::seed_from_u64(42))?;
+            println!("{}", game);
+            Ok(())
+        },
+        5 => {
+            flags.only(&[&COUNT_FLAGS[..], &["depth", "csv"]].concat())?;
+            // Plies to count, 0 counts to the end of the round
+            let depth: u8 = flags.value("depth", 0)?;
+            let csv = flags.switch("csv")?;
+
+            let table = TranspositionTable::new(table_size << 20, replacement);
+            let progress = Progress::new("divide", format, interval);
+            print_divide(&divide(&some_position()?.state, depth, &table, &progress), csv);
+            progress.report(Some(table.stats()));
+            Ok(())
+        },
+        6 => {
+            flags.only(&COUNT_FLAGS)?;
+            calculate_options(table_size, replacement, threads, true, None, false, &Progress::new("count", format, interval))
+        },
+        7 => {
+            flags.only(&PROGRESS_FLAGS)?;
+            let game = some_position()?;
+            for (depth, level) in reachable(&game.state).iter().enumerate() {
+                println!("{}: {} positions, {} paths, {} finished",
+                    depth, level.positions.separate_with_spaces(),
+                    level.paths.separate_with_spaces(), level.finished.separate_with_spaces()
+                );
+            }
+            Ok(())
+        },
+        8 => {
+            flags.only(&[&PROGRESS_FLAGS[..], &["probes", "horizon"]].concat())?;
+            let probes: u64 = flags.value("probes", 10_000)?;
+            // A number of moves, "round" or "game"
+            let (depth, refills) = match flags.text("horizon")?.unwrap_or("round") {
+                "round" => (None, false),
+                "game" => (None, true),
+                moves => (Some(moves.parse().map_err(|_| format!("Bad value for --horizon: {}", moves))?), true)
+            };
+
+            let mut game = Game::new(2, 42)?;
+            game.fill()?;
+            let progress = Progress::new("estimate", format, interval);
+            let size = estimate(&game.state, depth, refills, probes, &mut StdRng::from_entropy(), &progress);
+            progress.report(None);
+            for (depth, estimate) in size.depths.iter().enumerate() {
+                let (low, high) = estimate.interval();
+                println!("{}: {:.4e} ({:.4e} - {:.4e})", depth, estimate.mean, low, high);
+            }
+            let (low, high) = size.lines.interval();
+            println!("{} probes, {:.4e} lines ({:.4e} - {:.4e})", size.probes, size.lines.mean, low, high);
             Ok(())
         },
-        _ => Err("Not a valid program")
+        9 => {
+            flags.only(&[&PROGRESS_FLAGS[..], &["games"]].concat())?;
+            // Random games played to the end
+            let games: u64 = flags.value("games", 1_000)?;
+            let progress = Progress::new("self-play", format, interval);
+            progress.add_total(games);
+            for seed in 0..games {
+                run(StdRng::seed_from_u64(seed), &progress)?;
+                progress.finish(1);
+                progress.tick(None);
+            }
+            progress.report(None);
+            Ok(())
+        },
+        _ => Err("Not a valid program".into())
     }
 }
 
-fn run(rng: StdRng) -> Result<(), &'static str> {
+fn some_position() -> Result<Game, AzulError> {
+    let mut game = some_game()?;
+
+    game.do_move(game.validate(GameMove(2, Tile::Red, 3))?)?;
+    game.do_move(game.validate(GameMove(5, Tile::Yellow, 2))?)?;
+
+    game.do_move(game.validate(GameMove(3, Tile::Blue, 2))?)?;
+    //game.do_move(GameMove(0, Tile::Black, 4))?;
+
+    //game.do_move(GameMove(5, Tile::Black, 1))?;
+    //game.do_move(GameMove(0, Tile::Blue, 3))?;
+    Ok(game)
+}
+
+/// Plays random moves until the game is over. Always taking the first move
+/// only ever fills the floor, and the game never ends
+fn run(mut rng: StdRng, progress: &Progress) -> Result<(), AzulError> {
 
-    let mut game = Game::new(2)?;
-    game.fill(StdRng::from_entropy())?;
+    let mut game = Game::new(2, rng.gen())?;
+    game.fill()?;
+    let mut nodes = progress.nodes();
 
-    let mut all_err = false;
-    while !all_err {
+    while !game.is_over() {
         //println!("{:#?}", game);
-        all_err = true;
-        let mut game_move:Option<GameMove> = Some(GameMove::default());
-        while let Some(i) =  game_move {
-            match game.do_move(i) {
-                Err(e) => {
-                    //println!("{:?}: {}", i, e);
-                    game_move = i.into_iter().next();
-                },
-                Ok(_) => {
-                    all_err = false;
-                    break;
-                }
-            }
+        let moves = game.legal_moves();
+        if moves.is_empty() {
+            break;
         }
+        game.do_move(moves[rng.gen_range(0..moves.len())])?;
+        nodes.node(|| None);
     }
     Ok(())
 }
 
-fn calculate_options() -> Result<(), &'static str> {
+/// With `exact` the first two moves are counted for real, next to the upper bound
+fn calculate_options(table_size: usize, replacement: Replacement, threads: usize, exact: bool, checkpoint: Option<&str>, save_table: bool, progress: &Progress) -> Result<(), Box<dyn std::error::Error>> {
     let mut game = complicated()?;
     println!("{:#?}", game);
+    let table = TranspositionTable::new(table_size << 20, replacement);
+
+    if exact {
+        let options = count_exact(game.state, 2, &table, threads, progress);
+        println!("exact: {}", options);
+    }
 
     // We know how many possibilities there are the first round...
-    game.do_move(GameMove(1, Tile::Blue, 0))?;
+    game.do_move(game.validate(GameMove(1, Tile::Blue, 0))?)?;
     // We also know how many possiblities there are the second round.
     // NB: THIS STEP INTRODUCES ERROR, THE REAL NUMBER WILL BE SMALLER THAN THIS
-    game.do_move(GameMove(0, Tile::Yellow, 0))?;
+    game.do_move(game.validate(GameMove(0, Tile::Yellow, 0))?)?;
 
-    let options = count_options(game, 1, 5);
-    println!("{}", options * (20 * 6)*(19 * 6));
+    let options = count(game.state, 1, &table, threads, progress, checkpoint, save_table)?;
+    match exact {
+        true => println!("upper bound: {}", options * (20 * 6)*(19 * 6)),
+        false => println!("{}", options * (20 * 6)*(19 * 6))
+    }
+    progress.report(Some(table.stats()));
+    print_stats(&table);
 
     Ok(())
 }
 
-#[cached(size=45_000_000, key = "State", convert = r#"{ _game.state }"#)]
-fn count_options(_game: Game, depth: u8, treshold: u8) -> u128 {
-    let before = std::time::Instant::now();
+fn print_stats(table: &TranspositionTable<u128>) {
+    let stats = table.stats();
+    println!("table: {} entries, {} hits, {} misses, {} collisions, {:.1}% hit rate",
+        table.capacity().separate_with_spaces(), stats.hits.separate_with_spaces(),
+        stats.misses.separate_with_spaces(), stats.collisions.separate_with_spaces(),
+        stats.hit_rate() * 100.0
+    );
+}
 
-    let mut sum = 0;
-    let mut all_failed = true;
+/// Every legal move from `state`, with how many lines follow it.
+/// A depth of 0 counts to the end of the round like `count_options`
+fn divide(state: &State, depth: u8, table: &TranspositionTable<u128>, progress: &Progress) -> Vec<(GameMove, u128)> {
+    let mut moves = Vec::new();
+    let legal_moves = state.legal_moves();
+    progress.add_total(legal_moves.len() as u64);
+    let mut nodes = progress.nodes();
+    for valid_move in legal_moves {
+        let mut new_state = *state;
+        new_state.do_move(valid_move);
+        let count = match depth {
+            0 => count_options(new_state, 1, table, &mut nodes),
+            _ => perft(&new_state, depth - 1)
+        };
+        progress.finish(1);
+        moves.push((valid_move.game_move(), count));
+    }
+    moves
+}
 
-    let mut multiplier = 1;
+fn print_divide(moves: &[(GameMove, u128)], csv: bool) {
+    if csv {
+        println!("source,color,pattern,count");
+    }
+    for (GameMove(source, color, pattern), count) in moves {
+        match csv {
+            true => println!("{},{:?},{},{}", source, color, pattern, count),
+            false => println!("{} {:?} {}: {}", source, color, pattern, count.separate_with_spaces())
+        }
+    }
+    if !csv {
+        let total = moves.iter().map(|(_, count)| count).sum::<u128>();
+        println!("{} moves, {} total", moves.len(), total.separate_with_spaces());
+    }
+}
 
-    // Upper bound calculation by skipping first depth and assuming most complicated possible game as multiplier
-    let game = match depth {
-        0 => {
-            let mut new_game = _game.clone();
-            for i in GameMoveIter::new(2).next() {
-                match new_game.do_move(i) {
-                    Ok(_) => break,
-                    Err(_) => continue
-                }
+/// Positions a number of moves into the round
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Level {
+    /// Different positions
+    positions: usize,
+    /// Lines of play leading to them
+    paths: u128,
+    /// Positions where the round is over
+    finished: usize,
+}
+
+/// Goes through the round breadth first, merging lines that reach the same position
+fn reachable(state: &State) -> Vec<Level> {
+    let mut levels = Vec::new();
+    let mut positions = std::collections::HashMap::new();
+    positions.insert(*state, 1u128);
+
+    while !positions.is_empty() {
+        let mut level = Level { positions: positions.len(), ..Level::default() };
+        let mut next = std::collections::HashMap::new();
+        for (state, paths) in positions {
+            level.paths += paths;
+            let legal_moves = state.legal_moves();
+            if legal_moves.is_empty() {
+                level.finished += 1;
             }
-            multiplier = 20*6;
-            new_game
+            for valid_move in legal_moves {
+                let mut new_state = state;
+                new_state.do_move(valid_move);
+                *next.entry(new_state).or_insert(0) += paths;
+            }
+        }
+        levels.push(level);
+        positions = next;
+    }
+    levels
+}
+
+/// Lines of play `depth` moves deep, or shorter when the round ends first
+fn perft(state: &State, depth: u8) -> u128 {
+    if depth == 0 {
+        return 1;
+    }
+    let legal_moves = state.legal_moves();
+    if legal_moves.is_empty() {
+        return 1;
+    }
+    let mut sum = 0;
+    for valid_move in legal_moves {
+        let mut new_state = *state;
+        new_state.do_move(valid_move);
+        sum += perft(&new_state, depth - 1);
+    }
+    sum
+}
+
+// Positions that are split off the top of the tree, for every thread
+const WORK_PER_THREAD: usize = 16;
+
+/// Same as `count_options`, but the top plies are split between `threads` workers
+/// sharing the transposition table. The root moves are always split off,
+/// so `progress` can tell how many of them are done
+fn count_options_parallel(state: State, depth: u8, table: &TranspositionTable<u128>, threads: usize, progress: &Progress) -> u128 {
+    let mut work = vec![(state, 1)];
+    let mut depth = depth;
+    for ply in 0..2 {
+        if ply != 0 && work.len() >= threads * WORK_PER_THREAD {
+            break;
+        }
+        work = expand(work, depth);
+        depth += 1;
+    }
+    count_work(&work, depth, table, threads, progress)
+}
+
+// Seconds between checkpoints
+const CHECKPOINT_INTERVAL: u64 = 60;
+
+fn count(state: State, depth: u8, table: &TranspositionTable<u128>, threads: usize, progress: &Progress, checkpoint: Option<&str>, save_table: bool) -> std::io::Result<u128> {
+    match checkpoint {
+        Some(path) => {
+            let interval = std::time::Duration::from_secs(CHECKPOINT_INTERVAL);
+            let checkpointer = Checkpointer::open(std::path::Path::new(path), interval, save_table, state.zobrist(), table)?;
+            count_options_resumable(state, depth, table, threads, progress, &checkpointer)
         },
-        _ => {
-            _game
+        None => Ok(count_options_parallel(state, depth, table, threads, progress))
+    }
+}
+
+/// Same as `count_options_parallel`, but every finished root subtree goes in the checkpoint,
+/// and the ones that were already there aren't counted again
+fn count_options_resumable(state: State, depth: u8, table: &TranspositionTable<u128>, threads: usize, progress: &Progress, checkpointer: &Checkpointer) -> std::io::Result<u128> {
+    let work = expand(vec![(state, 1)], depth);
+    let done = checkpointer.checkpoint().done;
+    for (index, (key, _)) in &done {
+        if work.get(*index).map(|(state, _)| state.zobrist()) != Some(*key) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Checkpoint doesn't match the root moves"));
         }
-    };
+    }
+    progress.add_total(work.len() as u64);
+    progress.skip(done.len() as u64);
+
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..std::cmp::max(threads, 1)).map(|_| scope.spawn(|| {
+            let mut nodes = progress.nodes();
+            loop {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                match work.get(i) {
+                    Some(_) if done.contains_key(&i) => continue,
+                    Some((state, _)) => {
+                        let count = count_options(*state, depth + 1, table, &mut nodes);
+                        checkpointer.finish(i, state.zobrist(), count, table)?;
+                        progress.finish(1);
+                    },
+                    None => return Ok(())
+                }
+            }
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("worker panicked")).collect::<std::io::Result<()>>()
+    })?;
+    checkpointer.save(table)?;
 
-    let i = GameMoveIter::new(2);
+    let done = checkpointer.checkpoint().done;
+    Ok(work.iter().enumerate().map(|(i, (_, weight))| weight * done[&i].1).sum::<u128>())
+}
+
+/// Splits counting `state` into units `plies` moves deep, and writes them to `directory`
+/// to be done by `work`. Returns how many there are
+fn split(state: State, depth: u8, plies: u8, directory: &std::path::Path) -> std::io::Result<usize> {
+    let mut work = vec![(state, 1)];
+    for ply in 0..plies {
+        work = expand(work, depth + ply);
+    }
+
+    std::fs::create_dir_all(directory)?;
+    for (i, (state, weight)) in work.iter().enumerate() {
+        let unit = Unit { state: *state, depth: depth + plies, weight: *weight, count: None };
+        unit.save(&directory.join(format!("{:06}.unit", i)))?;
+    }
+    Ok(work.len())
+}
 
-    for game_move in i {
-        //println!("{:?}", game_move);
-        if game.is_legal(game_move).is_err() {
+/// Counts a unit written by `split`, and writes it back with the count next to it
+fn work(path: &std::path::Path, table: &TranspositionTable<u128>, threads: usize, progress: &Progress) -> std::io::Result<u128> {
+    let mut unit = Unit::load(path)?;
+    if let Some(count) = unit.count {
+        return Ok(count);
+    }
+    let count = count_options_parallel(unit.state, unit.depth, table, threads, progress);
+    unit.count = Some(count);
+    unit.save(&path.with_extension("result"))?;
+    Ok(count)
+}
+
+/// Adds up the results of all units in `directory`, every unit has to be done
+fn merge(directory: &std::path::Path) -> std::io::Result<u128> {
+    let mut sum = 0;
+    let mut missing = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("unit") {
             continue;
-        };
-        let mut new_game = game.clone();
-        let r = new_game.do_move(game_move);
-        match r {
-            Ok(_) => sum += {/*println!("{}", depth);*/ all_failed = false; /*coz::progress!("OK");*/ multiplier * count_options(new_game, depth + 1, treshold)},
-            Err(_) => continue
-        };
+        }
+        let unit = Unit::load(&path)?;
+        let result_path = path.with_extension("result");
+        if !result_path.exists() {
+            missing += 1;
+            continue;
+        }
+        let result = Unit::load(&result_path)?;
+        match result.count {
+            Some(count) if result.state == unit.state && result.weight == unit.weight => sum += unit.weight * count,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("{} doesn't belong to {}", result_path.display(), path.display())))
+        }
+    }
+    if missing != 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} units aren't done yet", missing)));
+    }
+    Ok(sum)
+}
+
+/// Counts the first `plies` moves for real instead of using the upper bound multipliers.
+/// Moves leading to the same position up to symmetry, like taking from two identical
+/// factories, are only counted once, and the transposition table catches the rest
+fn count_exact(state: State, plies: u8, table: &TranspositionTable<u128>, threads: usize, progress: &Progress) -> u128 {
+    let mut work = vec![(state, 1)];
+    // Starting at depth 1, so there are no multipliers
+    for depth in 1..=plies {
+        work = expand(work, depth);
+    }
+    count_work(&work, plies + 1, table, threads, progress)
+}
+
+/// Does every move from the positions in `work`, each one counting `weight` times.
+/// Returns the positions after, with symmetric ones merged. Positions where the round
+/// is already over are kept as they are, they still count as one line
+fn expand(work: Vec<(State, u128)>, depth: u8) -> Vec<(State, u128)> {
+    let mut next: Vec<(State, u128)> = Vec::new();
+    let mut seen: std::collections::HashMap<State, usize> = std::collections::HashMap::new();
+    let mut add = |state: State, weight: u128| {
+        // Symmetric positions have the same number of options. The whole state is the key,
+        // two positions with the same hash aren't merged
+        let key = state.canonical();
+        match seen.get(&key) {
+            Some(i) => next[*i].1 += weight,
+            None => {
+                seen.insert(key, next.len());
+                next.push((state, weight));
+            }
+        }
     };
+    for (state, weight) in work {
+        let (state, multiplier) = upper_bound_root(state, depth);
+        let legal_moves = state.legal_moves();
+        if legal_moves.is_empty() {
+            add(state, weight);
+        }
+        for valid_move in legal_moves {
+            let mut new_state = state;
+            new_state.do_move(valid_move);
+            add(new_state, weight * multiplier);
+        }
+    }
+    next
+}
 
-    if all_failed {
-        if depth <= treshold {
-            println!("{}: ALL FAILED!", depth);
+/// Counts all positions in `work` times their weight, split between `threads` workers
+fn count_work(work: &[(State, u128)], depth: u8, table: &TranspositionTable<u128>, threads: usize, progress: &Progress) -> u128 {
+    progress.add_total(work.len() as u64);
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let sums = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..std::cmp::max(threads, 1)).map(|_| scope.spawn(|| {
+            let mut sum = 0;
+            let mut nodes = progress.nodes();
+            loop {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                match work.get(i) {
+                    Some((state, weight)) => {
+                        sum += weight * count_options(*state, depth, table, &mut nodes);
+                        progress.finish(1);
+                    },
+                    None => return sum
+                }
+            }
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("worker panicked")).collect::<Vec<u128>>()
+    });
+    sums.iter().sum()
+}
+
+// Upper bound calculation by skipping first depth and assuming most complicated possible game as multiplier
+fn upper_bound_root(state: State, depth: u8) -> (State, u128) {
+    match depth {
+        0 => {
+            let mut new_state = state;
+            if let Some(valid_move) = state.legal_moves().first() {
+                new_state.do_move(*valid_move);
+            }
+            (new_state, 20*6)
+        },
+        _ => (state, 1)
+    }
+}
+
+// Tiles left from which positions are looked up by their canonical form
+const CANONICAL_TILES: u8 = 10;
+
+fn count_options(_state: State, depth: u8, table: &TranspositionTable<u128>, nodes: &mut Nodes) -> u128 {
+    nodes.node(|| Some(table.stats()));
+    // Symmetric positions have the same count to the end of the round, so they share an entry.
+    // Finding the canonical form costs more than counting a small subtree, so those keep their own
+    let key = if _state.tiles_left() >= CANONICAL_TILES { _state.canonical().zobrist() } else { _state.zobrist() };
+    if depth != 0 {
+        if let Some(sum) = table.get(key) {
+            return sum;
         }
+    }
+
+    let mut sum = 0;
+
+    let (state, multiplier) = upper_bound_root(_state, depth);
+
+    let legal_moves = state.legal_moves();
+    if legal_moves.is_empty() {
         return 1;
     }
 
-    if depth <= treshold {
-        let elapsed = before.elapsed();
-        let hashrate = sum / std::cmp::max(elapsed.as_millis() as u128, 1u128) * 1000u128;
-        println!("{}: sum: {}, took +{:?}: {}/s",
-                  depth,   sum,      elapsed, hashrate.separate_with_spaces()
-        );
+    for valid_move in legal_moves {
+        //println!("{:?}", valid_move);
+        let mut new_state = state;
+        // Only the state, so that counting stops at the end of the round
+        new_state.do_move(valid_move);
+        sum += /*coz::progress!("OK");*/ multiplier * count_options(new_state, depth + 1, table, nodes);
+    };
+
+    // The root is counted with a multiplier, so it's not the same as the position itself
+    if depth != 0 {
+        // More tiles left is a bigger subtree, and more work saved
+        table.insert(key, state.tiles_left(), sum);
     }
-    return sum;
+    sum
 
     /*game_move.iter_mut().map(|x| {
         let mut new_game = game.clone();
@@ -161,3 +655,179 @@ fn count_options(_game: Game, depth: u8, treshold: u8) -> u128 {
     .sum::<u128>()*/
 
 }
+
+/// `complicated` after random moves from `seed`, until only `tiles_left` tiles are left
+#[cfg(test)]
+fn random_position(seed: u64, tiles_left: u8) -> Result<Game, AzulError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut game = complicated()?;
+    while game.tiles_left() > tiles_left {
+        let moves = game.legal_moves();
+        game.do_move(moves[rng.gen_range(0..moves.len())])?;
+    }
+    Ok(game)
+}
+
+#[test]
+fn parallel_count() -> Result<(), AzulError> {
+    let game = random_position(16, 9)?;
+
+    for depth in 0..2 {
+        let sequential = count_options(game.state, depth, &TranspositionTable::new(1 << 20, Replacement::Deeper), &mut Progress::quiet().nodes());
+        for threads in 2..=4 {
+            let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+            assert_eq!(count_options_parallel(game.state, depth, &table, threads, &Progress::quiet()), sequential);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn divide_count() -> Result<(), AzulError> {
+    let game = random_position(17, 9)?;
+
+    let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+    let moves = divide(&game.state, 0, &table, &Progress::quiet());
+    assert_eq!(moves.len(), game.legal_moves().len());
+    let total = moves.iter().map(|(_, count)| count).sum::<u128>();
+    assert_eq!(total, count_options(game.state, 1, &table, &mut Progress::quiet().nodes()));
+    // Deep enough to always reach the end of the round
+    assert_eq!(total, perft(&game.state, 10));
+
+    for depth in 1..4 {
+        let moves = divide(&game.state, depth, &table, &Progress::quiet());
+        assert_eq!(moves.iter().map(|(_, count)| count).sum::<u128>(), perft(&game.state, depth));
+    }
+    assert!(divide(&game.state, 1, &table, &Progress::quiet()).iter().all(|(_, count)| *count == 1));
+    Ok(())
+}
+
+#[test]
+fn exact_count() -> Result<(), AzulError> {
+    let game = random_position(18, 10)?;
+
+    let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+    let sequential = count_options(game.state, 1, &table, &mut Progress::quiet().nodes());
+    for plies in 0..3 {
+        for threads in 1..3 {
+            let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+            assert_eq!(count_exact(game.state, plies, &table, threads, &Progress::quiet()), sequential);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn reachable_positions() -> Result<(), AzulError> {
+    let game = random_position(19, 10)?;
+
+    fn paths(state: &State, depth: usize) -> u128 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut sum = 0;
+        for valid_move in state.legal_moves() {
+            let mut new_state = *state;
+            new_state.do_move(valid_move);
+            sum += paths(&new_state, depth - 1);
+        }
+        sum
+    }
+
+    let levels = reachable(&game.state);
+    assert_eq!(levels[0], Level { positions: 1, paths: 1, finished: 0 });
+    for (depth, level) in levels.iter().enumerate() {
+        assert_eq!(level.paths, paths(&game.state, depth));
+        assert!(level.positions as u128 <= level.paths);
+    }
+    assert_eq!(levels.last().map(|level| level.finished), levels.last().map(|level| level.positions));
+    assert!(levels.iter().map(|level| level.finished).sum::<usize>() > 0);
+    Ok(())
+}
+
+#[test]
+fn symmetric_count() -> Result<(), AzulError> {
+    let game = random_position(18, CANONICAL_TILES)?;
+    let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+    let count = count_options(game.state, 1, &table, &mut Progress::quiet().nodes());
+
+    // Swapping blue and red, the walls are still empty
+    let swapped = game.relabel(&[Tile::Red, Tile::Yellow, Tile::Blue, Tile::Black, Tile::Teal]);
+    assert_ne!(swapped.zobrist(), game.zobrist());
+    let stats = table.stats();
+    assert_eq!(count_options(swapped, 1, &table, &mut Progress::quiet().nodes()), count);
+    assert_eq!(table.stats().hits, stats.hits + 1);
+    assert_eq!(table.stats().misses, stats.misses);
+    Ok(())
+}
+
+#[test]
+fn resume_count() -> Result<(), Box<dyn std::error::Error>> {
+    let game = random_position(22, 7)?;
+    let expected = count_options(game.state, 1, &TranspositionTable::new(1 << 20, Replacement::Deeper), &mut Progress::quiet().nodes());
+
+    let directory = std::env::temp_dir().join(format!("mercury-resume-{}", std::process::id()));
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.join("count.checkpoint");
+
+    for save_table in [false, true].iter() {
+        let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+        let checkpointer = Checkpointer::open(&path, std::time::Duration::from_secs(0), *save_table, game.state.zobrist(), &table)?;
+        assert_eq!(count_options_resumable(game.state, 1, &table, 2, &Progress::quiet(), &checkpointer)?, expected);
+
+        // Pretend it died halfway through
+        let mut checkpoint = Checkpoint::load(&path)?;
+        let total = checkpoint.done.len();
+        let keep: Vec<usize> = checkpoint.done.keys().cloned().step_by(2).collect();
+        checkpoint.done.retain(|index, _| keep.contains(index));
+        checkpoint.save(&path)?;
+
+        let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+        let checkpointer = Checkpointer::open(&path, std::time::Duration::from_secs(60), *save_table, game.state.zobrist(), &table)?;
+        assert_eq!(checkpointer.checkpoint().done.len(), keep.len());
+        assert_eq!(table.stats().stores > 0, *save_table);
+        let progress = Progress::quiet();
+        assert_eq!(count_options_resumable(game.state, 1, &table, 2, &progress, &checkpointer)?, expected);
+        assert_eq!(Checkpoint::load(&path)?.done.len(), total);
+        // The subtrees from the checkpoint don't count towards the speed
+        let status = progress.status(None);
+        assert_eq!((status.done, status.total, status.skipped), (total as u64, total as u64, keep.len() as u64));
+
+        std::fs::remove_file(&path)?;
+    }
+
+    std::fs::remove_dir_all(&directory)?;
+    Ok(())
+}
+
+#[test]
+fn split_work_merge() -> Result<(), Box<dyn std::error::Error>> {
+    let game = random_position(23, 8)?;
+    let table = TranspositionTable::new(1 << 20, Replacement::Deeper);
+    let expected = count_options(game.state, 1, &table, &mut Progress::quiet().nodes());
+
+    let directory = std::env::temp_dir().join(format!("mercury-split-{}", std::process::id()));
+    for plies in 0..3 {
+        let units = split(game.state, 1, plies, &directory)?;
+        assert!(units > 0);
+        let first = directory.join("000000.unit");
+        assert!(merge(&directory).is_err());
+
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) == Some("unit") {
+                work(&path, &TranspositionTable::new(1 << 20, Replacement::Deeper), 2, &Progress::quiet())?;
+            }
+        }
+        assert_eq!(merge(&directory)?, expected);
+
+        // Units can be checked on their own
+        let unit = Unit::load(&first)?;
+        let result = Unit::load(&first.with_extension("result"))?;
+        assert_eq!(work(&first.with_extension("result"), &table, 1, &Progress::quiet())?, result.count.unwrap());
+        assert_eq!(count_options(unit.state, unit.depth, &table, &mut Progress::quiet().nodes()), result.count.unwrap());
+
+        std::fs::remove_dir_all(&directory)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use super::tt::Stats;
+
+// Nodes between adding to the shared count and looking at the clock, so the hot loops don't have to
+const NODES_PER_CHECK: u64 = 1 << 14;
+
+/// How progress is written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One line of text at a time
+    Human,
+    /// One JSON object on every line
+    Json,
+    /// Nothing at all
+    Quiet,
+}
+
+/// Where a long running task is at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub task: &'static str,
+    /// Units of work that are done, like root moves or games, out of `total`
+    pub done: u64,
+    pub total: u64,
+    /// Work that was done before this run, like subtrees from a checkpoint
+    pub skipped: u64,
+    /// Positions visited
+    pub nodes: u64,
+    pub elapsed: Duration,
+    /// Transposition table hit rate, if there is a table
+    pub hit_rate: Option<f64>,
+}
+impl Status {
+    pub fn nodes_per_second(&self) -> f64 {
+        self.nodes as f64 / self.elapsed.as_secs_f64().max(1e-3)
+    }
+    /// How long the rest will take at the speed so far, unknown until something is done
+    pub fn eta(&self) -> Option<Duration> {
+        let done = self.done.checked_sub(self.skipped)?;
+        if done == 0 || self.total < self.done {
+            return None;
+        }
+        Some(self.elapsed.mul_f64((self.total - self.done) as f64 / done as f64))
+    }
+    pub fn human(&self) -> String {
+        let mut line = format!("{}: {}/{} done, {} nodes, {:.0} nodes/s, {} elapsed",
+            self.task, self.done, self.total, self.nodes, self.nodes_per_second(), clock(self.elapsed)
+        );
+        if let Some(hit_rate) = self.hit_rate {
+            line += &format!(", {:.1}% table hits", hit_rate * 100.0);
+        }
+        if let Some(eta) = self.eta() {
+            line += &format!(", {} left", clock(eta));
+        }
+        line
+    }
+    pub fn json(&self) -> String {
+        let optional = |x: Option<f64>| x.map_or("null".to_string(), |x| format!("{:.3}", x));
+        format!("{{\"task\":\"{}\",\"done\":{},\"total\":{},\"nodes\":{},\"nodes_per_second\":{:.1},\"elapsed\":{:.3},\"hit_rate\":{},\"eta\":{}}}",
+            self.task, self.done, self.total, self.nodes, self.nodes_per_second(), self.elapsed.as_secs_f64(),
+            optional(self.hit_rate), optional(self.eta().map(|eta| eta.as_secs_f64()))
+        )
+    }
+}
+
+// Hours, minutes and seconds
+fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Keeps track of a long running task like counting, searching or self-play,
+/// and writes how it's going to stderr every `interval`. It can be shared between threads
+pub struct Progress {
+    task: &'static str,
+    format: Format,
+    interval: Duration,
+    started: Instant,
+    reported: Mutex<Instant>,
+    total: AtomicU64,
+    done: AtomicU64,
+    skipped: AtomicU64,
+    nodes: AtomicU64,
+}
+impl Progress {
+    pub fn new(task: &'static str, format: Format, interval: Duration) -> Progress {
+        Progress {
+            task,
+            format,
+            interval,
+            started: Instant::now(),
+            reported: Mutex::new(Instant::now()),
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            nodes: AtomicU64::new(0),
+        }
+    }
+    /// Keeps count, but never writes anything
+    #[cfg(test)]
+    pub fn quiet() -> Progress {
+        Progress::new("", Format::Quiet, Duration::MAX)
+    }
+    /// There are `n` more units of work to do
+    pub fn add_total(&self, n: u64) {
+        self.total.fetch_add(n, Ordering::Relaxed);
+    }
+    /// `n` units of work are done
+    pub fn finish(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+    /// `n` units of work were already done before, they don't count towards the speed
+    pub fn skip(&self, n: u64) {
+        self.skipped.fetch_add(n, Ordering::Relaxed);
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+    /// A node counter for one thread
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes { progress: self, count: 0 }
+    }
+    pub fn status(&self, stats: Option<Stats>) -> Status {
+        Status {
+            task: self.task,
+            done: self.done.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            nodes: self.nodes.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+            hit_rate: stats.map(|stats| stats.hit_rate()),
+        }
+    }
+    /// Reports if it's been `interval` since the last time
+    pub fn tick(&self, stats: Option<Stats>) {
+        if self.format == Format::Quiet {
+            return;
+        }
+        let mut reported = match self.reported.try_lock() {
+            Ok(reported) => reported,
+            // Someone else is looking already
+            Err(_) => return
+        };
+        if reported.elapsed() >= self.interval {
+            self.report(stats);
+            *reported = Instant::now();
+        }
+    }
+    /// Reports right away, like at the end of the task
+    pub fn report(&self, stats: Option<Stats>) {
+        let status = self.status(stats);
+        match self.format {
+            Format::Human => eprintln!("{}", status.human()),
+            Format::Json => eprintln!("{}", status.json()),
+            Format::Quiet => {}
+        }
+    }
+}
+
+/// Counts nodes on its own, and adds them to the `Progress` every `NODES_PER_CHECK` nodes
+/// and when it's dropped. That way threads don't fight over the shared count
+pub struct Nodes<'a> {
+    progress: &'a Progress,
+    count: u64,
+}
+impl<'a> Nodes<'a> {
+    /// Counts a node, and every so often reports with the table stats from `stats`
+    pub fn node(&mut self, stats: impl FnOnce() -> Option<Stats>) {
+        self.count += 1;
+        if self.count == NODES_PER_CHECK {
+            self.flush();
+            self.progress.tick(stats());
+        }
+    }
+    /// Adds the nodes so far to the `Progress`
+    pub fn flush(&mut self) {
+        self.progress.nodes.fetch_add(self.count, Ordering::Relaxed);
+        self.count = 0;
+    }
+}
+impl<'a> Drop for Nodes<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[test]
+fn progress_status() {
+    let progress = Progress::new("count", Format::Quiet, Duration::from_secs(1));
+    progress.add_total(10);
+    progress.skip(2);
+    let mut nodes = progress.nodes();
+    for _ in 0..NODES_PER_CHECK + 1 {
+        nodes.node(|| None);
+    }
+    // Only whole batches until it's flushed
+    assert_eq!(progress.status(None).nodes, NODES_PER_CHECK);
+    drop(nodes);
+    let status = progress.status(None);
+    assert_eq!((status.done, status.total, status.nodes), (2, 10, NODES_PER_CHECK + 1));
+    // Only skipped work, so there's no telling how fast it goes
+    assert_eq!(status.eta(), None);
+
+    progress.finish(2);
+    let status = Status { elapsed: Duration::from_secs(60), ..progress.status(None) };
+    // Two done in a minute, six to go
+    assert_eq!(status.eta(), Some(Duration::from_secs(180)));
+    assert_eq!(status.human(), format!("count: 4/10 done, {} nodes, {:.0} nodes/s, 0:01:00 elapsed, 0:03:00 left",
+        NODES_PER_CHECK + 1, (NODES_PER_CHECK + 1) as f64 / 60.0));
+
+    let stats = Stats { hits: 1, misses: 3, stores: 0, collisions: 0 };
+    let status = Status { hit_rate: Some(stats.hit_rate()), ..status };
+    assert!(status.human().ends_with("25.0% table hits, 0:03:00 left"));
+    let json = status.json();
+    assert!(json.starts_with("{\"task\":\"count\",\"done\":4,\"total\":10,"));
+    assert!(json.contains("\"hit_rate\":0.250,\"eta\":180.000}"));
+    assert_eq!(Status { done: 0, skipped: 0, hit_rate: None, ..status }.json().matches("null").count(), 2);
+}
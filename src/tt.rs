@@ -0,0 +1,222 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Entries sharing an index, and a lock
+const BUCKET_SIZE: usize = 4;
+
+/// What to throw out when a bucket is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// The newest entry always goes in, over the shallowest one in the bucket
+    Always,
+    /// Only replace an entry that saved as much work or less than the new one
+    Deeper,
+}
+/// By name, like `always` or `deeper`
+impl std::str::FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Replacement, String> {
+        match text {
+            "always" => Ok(Replacement::Always),
+            "deeper" => Ok(Replacement::Deeper),
+            _ => Err(format!("Unknown replacement policy {}", text))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub stores: u64,
+    /// Stores that threw out a different position
+    pub collisions: u64,
+}
+impl Stats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Entry<V> {
+    key: u64,
+    depth: u8,
+    filled: bool,
+    value: V,
+}
+
+/// A fixed size hash table for positions, keyed by their Zobrist hash.
+/// Every bucket has its own lock, so it can be shared between threads
+pub struct TranspositionTable<V> {
+    buckets: Box<[Mutex<[Entry<V>; BUCKET_SIZE]>]>,
+    mask: usize,
+    replacement: Replacement,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stores: AtomicU64,
+    collisions: AtomicU64,
+}
+impl<V: Copy + Default> TranspositionTable<V> {
+    /// A table taking up at most `bytes` of memory, but always at least one bucket
+    pub fn new(bytes: usize, replacement: Replacement) -> TranspositionTable<V> {
+        let bucket = std::mem::size_of::<Mutex<[Entry<V>; BUCKET_SIZE]>>();
+        let mut n = 1;
+        while n * 2 * bucket <= bytes {
+            n *= 2;
+        }
+        let buckets = (0..n)
+            .map(|_| Mutex::new([Entry::default(); BUCKET_SIZE]))
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+
+        TranspositionTable {
+            buckets,
+            mask: n - 1,
+            replacement,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stores: AtomicU64::new(0),
+            collisions: AtomicU64::new(0),
+        }
+    }
+    /// How many positions fit in the table
+    pub fn capacity(&self) -> usize {
+        self.buckets.len() * BUCKET_SIZE
+    }
+    fn bucket(&self, key: u64) -> std::sync::MutexGuard<'_, [Entry<V>; BUCKET_SIZE]> {
+        // A panic while holding the lock can't leave an entry half written
+        match self.buckets[key as usize & self.mask].lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    pub fn get(&self, key: u64) -> Option<V> {
+        let bucket = self.bucket(key);
+        match bucket.iter().find(|entry| entry.filled && entry.key == key) {
+            Some(entry) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value)
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+    /// Stores a value, `depth` is how much work it saves compared to other entries
+    pub fn insert(&self, key: u64, depth: u8, value: V) {
+        let mut bucket = self.bucket(key);
+        self.stores.fetch_add(1, Ordering::Relaxed);
+
+        let slot = match bucket.iter().position(|entry| !entry.filled || entry.key == key) {
+            Some(slot) => slot,
+            None => {
+                let (slot, shallowest) = bucket.iter().enumerate()
+                    .min_by_key(|(_, entry)| entry.depth)
+                    .map(|(slot, entry)| (slot, entry.depth))
+                    .unwrap_or((0, 0));
+                if self.replacement == Replacement::Deeper && shallowest > depth {
+                    return;
+                }
+                self.collisions.fetch_add(1, Ordering::Relaxed);
+                slot
+            }
+        };
+        bucket[slot] = Entry { key, depth, filled: true, value };
+    }
+    /// Empties the table and its stats, like a new one but without allocating again
+    pub fn clear(&self) {
+        for bucket in self.buckets.iter() {
+            let mut bucket = match bucket.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            *bucket = [Entry::default(); BUCKET_SIZE];
+        }
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.stores.store(0, Ordering::Relaxed);
+        self.collisions.store(0, Ordering::Relaxed);
+    }
+    /// Goes through every entry in the table, with its key and depth
+    pub fn for_each(&self, mut f: impl FnMut(u64, u8, V)) {
+        for bucket in self.buckets.iter() {
+            let bucket = match bucket.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for entry in bucket.iter().filter(|entry| entry.filled) {
+                f(entry.key, entry.depth, entry.value);
+            }
+        }
+    }
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stores: self.stores.load(Ordering::Relaxed),
+            collisions: self.collisions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[test]
+fn insert_get() {
+    let table = TranspositionTable::<u128>::new(1 << 16, Replacement::Always);
+    assert!(table.capacity() > 0);
+    assert_eq!(table.get(42), None);
+
+    table.insert(42, 1, 1337);
+    assert_eq!(table.get(42), Some(1337));
+    table.insert(42, 1, 7);
+    assert_eq!(table.get(42), Some(7));
+
+    let stats = table.stats();
+    assert_eq!(stats, Stats { hits: 2, misses: 1, stores: 2, collisions: 0 });
+    assert!((stats.hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+
+    table.clear();
+    assert_eq!(table.get(42), None);
+    assert_eq!(table.stats().hits, 0);
+}
+
+#[test]
+fn replacement() {
+    assert_eq!("always".parse(), Ok(Replacement::Always));
+    assert_eq!("deeper".parse(), Ok(Replacement::Deeper));
+    assert!("newest".parse::<Replacement>().is_err());
+
+    // A single bucket, so every key fights over the same slots
+    for replacement in [Replacement::Always, Replacement::Deeper].iter() {
+        let table = TranspositionTable::<u64>::new(0, *replacement);
+        assert_eq!(table.capacity(), BUCKET_SIZE);
+        for key in 0..BUCKET_SIZE as u64 {
+            table.insert(key, 5, key);
+        }
+        assert_eq!(table.stats().collisions, 0);
+
+        table.insert(100, 1, 100);
+        match replacement {
+            Replacement::Always => {
+                assert_eq!(table.get(100), Some(100));
+                assert_eq!(table.stats().collisions, 1);
+            },
+            Replacement::Deeper => {
+                assert_eq!(table.get(100), None);
+                assert_eq!(table.stats().collisions, 0);
+            }
+        }
+
+        // Deeper entries always make it in
+        table.insert(200, 9, 200);
+        assert_eq!(table.get(200), Some(200));
+        let kept = (0..BUCKET_SIZE as u64).filter(|key| table.get(*key).is_some()).count();
+        assert_eq!(kept, BUCKET_SIZE - 1);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use super::azul::State;
+use super::checkpoint::temporary;
+
+const HEADER: &str = "mercury unit 1";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A piece of a count that can be done on its own, in another process or on another machine.
+/// Once it's done it knows its count too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unit {
+    pub state: State,
+    /// How deep into the count the position is
+    pub depth: u8,
+    /// How many times the position counts towards the total
+    pub weight: u128,
+    pub count: Option<u128>,
+}
+impl Unit {
+    /// Reads a unit written by `save`: a header, and then one line for every field
+    pub fn load(path: &Path) -> io::Result<Unit> {
+        let file = io::BufReader::new(std::fs::File::open(path)?);
+        let mut lines = file.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid(format!("{} is not a unit", path.display())));
+        }
+
+        let (mut state, mut depth, mut weight, mut count) = (None, None, None, None);
+        for line in lines {
+            let line = line?;
+            let (name, value) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+            let bad = || invalid(format!("Bad {} in {}", name, path.display()));
+            match name {
+                "position" => state = Some(value.parse::<State>()
+                    .map_err(|e| invalid(format!("{} in {}", e, path.display())))?),
+                "depth" => depth = Some(value.parse().map_err(|_| bad())?),
+                "weight" => weight = Some(value.parse().map_err(|_| bad())?),
+                "count" => count = Some(value.parse().map_err(|_| bad())?),
+                _ => return Err(invalid(format!("Unknown field {} in {}", name, path.display())))
+            }
+        }
+
+        match (state, depth, weight) {
+            (Some(state), Some(depth), Some(weight)) => Ok(Unit { state, depth, weight, count }),
+            _ => Err(invalid(format!("{} is missing fields", path.display())))
+        }
+    }
+    /// Writes to a temporary file first, so a crash while saving doesn't leave half a unit
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temporary = temporary(path);
+        let mut file = io::BufWriter::new(std::fs::File::create(&temporary)?);
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "position {}", self.state)?;
+        writeln!(file, "depth {}", self.depth)?;
+        writeln!(file, "weight {}", self.weight)?;
+        if let Some(count) = self.count {
+            writeln!(file, "count {}", count)?;
+        }
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(temporary, path)
+    }
+}
+
+#[test]
+fn save_load() -> io::Result<()> {
+    let directory = std::env::temp_dir().join(format!("mercury-unit-{}", std::process::id()));
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.join("0.unit");
+
+    let mut game = super::azul::Game::new(3, 23).map_err(|e| invalid(e.to_string()))?;
+    game.fill().map_err(|e| invalid(e.to_string()))?;
+    let state = game.state;
+    let mut unit = Unit { state, depth: 2, weight: 6, count: None };
+    unit.save(&path)?;
+    assert_eq!(Unit::load(&path)?, unit);
+    unit.count = Some(u128::MAX);
+    unit.save(&path)?;
+    assert_eq!(Unit::load(&path)?, unit);
+
+    std::fs::write(&path, format!("{}\nposition {}\ndepth 2\n", HEADER, state))?;
+    assert!(Unit::load(&path).is_err());
+    std::fs::write(&path, format!("{}\nposition nothing\ndepth 2\nweight 1\n", HEADER))?;
+    assert!(Unit::load(&path).is_err());
+
+    std::fs::remove_dir_all(&directory)
+}